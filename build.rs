@@ -2,8 +2,6 @@ use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 
-use sass_rs;
-
 const SCSS_FILE: &str = "src/style.scss";
 const CSS_FILE: &str = "static/style.css";
 
@@ -0,0 +1,211 @@
+use actix_web::web;
+use serde_derive::{Deserialize, Serialize};
+use super::{AppState, Article, Meta, find_article, is_unlocked};
+
+const DEFAULT_PER_PAGE: usize = 10;
+const MAX_PER_PAGE: usize = 50;
+
+#[derive(Serialize)]
+struct ArticleSummary<'a> {
+    name: &'a str,
+    url: String,
+    title: &'a str,
+    description: &'a str,
+    date: String,
+    lang: &'a str,
+    tags: &'a [String],
+    authors: &'a [String],
+}
+
+#[derive(Serialize)]
+struct ArticleList<'a> {
+    page: usize,
+    per_page: usize,
+    total: usize,
+    articles: Vec<ArticleSummary<'a>>,
+}
+
+#[derive(Serialize)]
+struct ArticleDetail<'a> {
+    #[serde(flatten)]
+    summary: ArticleSummary<'a>,
+    translation: Option<&'a str>,
+    html: &'a str,
+}
+
+#[derive(Serialize)]
+struct TagEntry<'a> {
+    name: &'a str,
+    url: String,
+    count: i32,
+    description: &'a str,
+    aliases: &'a [String],
+    parent: Option<&'a str>,
+    children: &'a [String],
+}
+
+#[derive(Serialize)]
+struct ProjectEntry<'a> {
+    name: &'a str,
+    title: &'a str,
+    order: i32,
+    html: &'a str,
+}
+
+#[derive(Serialize)]
+struct CommentEntry {
+    index: i64,
+    reply_to: Option<i64>,
+    author: Option<String>,
+    tripcode: Option<String>,
+    website: Option<String>,
+    timestamp: u64,
+    date: String,
+    html: String,
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    error: &'static str,
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+fn summary<'a>(data: &AppState, a: &'a Article) -> ArticleSummary<'a> {
+    ArticleSummary {
+        name: &a.name,
+        url: format!("{}/a/{}", data.config.base_url, a.name),
+        title: &a.title,
+        description: &a.description,
+        date: a.date.to_string(),
+        lang: &a.lang,
+        tags: &a.tags,
+        authors: &a.author,
+    }
+}
+
+/// The items on a 1-based page, pages past the end are empty.
+fn page_of<T>(items: &[T], page: usize, per_page: usize) -> &[T] {
+    let start = page.saturating_sub(1).saturating_mul(per_page).min(items.len());
+    &items[start..(start + per_page).min(items.len())]
+}
+
+fn not_found() -> actix_web::HttpResponse {
+    actix_web::HttpResponse::NotFound().json(ApiError { error: "not found" })
+}
+
+/// Looks up an article the request is allowed to see, private articles need the unlock cookie like on the site.
+fn visible_article<'a>(req: &web::HttpRequest, data: &AppState, meta: &'a Meta, name: &str) -> Option<&'a (Article, String)> {
+    find_article(req, data, meta, name).filter(|(a, _)| is_unlocked(req, &data.cookie_key, a))
+}
+
+async fn articles(web::Query(query): web::Query<PageQuery>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let meta = data.meta();
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+    let page = query.page.unwrap_or(1).max(1);
+    let all = &meta.recent_articles;
+    actix_web::HttpResponse::Ok().json(ArticleList {
+        page,
+        per_page,
+        total: all.len(),
+        articles: page_of(all, page, per_page).iter().map(|a| summary(&data, a)).collect(),
+    })
+}
+
+async fn article(req: web::HttpRequest, web::Path(name): web::Path<String>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let meta = data.meta();
+    match visible_article(&req, &data, &meta, &name) {
+        Some((a, html)) => actix_web::HttpResponse::Ok().json(ArticleDetail {
+            summary: summary(&data, a),
+            translation: a.translation.as_deref(),
+            html,
+        }),
+        None => not_found(),
+    }
+}
+
+async fn comments(req: web::HttpRequest, web::Path(name): web::Path<String>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let meta = data.meta();
+    if visible_article(&req, &data, &meta, &name).is_none() {
+        return not_found();
+    }
+    use chrono::TimeZone;
+    let entries: Vec<CommentEntry> = data.comments.approved(&name).into_iter().filter(|c| !c.deleted).map(|c| CommentEntry {
+        index: c.post_index,
+        reply_to: c.reply_to,
+        author: c.author,
+        tripcode: c.tripcode,
+        website: c.website,
+        timestamp: c.timestamp,
+        date: chrono::Utc.timestamp(c.timestamp as i64, 0).to_rfc3339(),
+        html: c.text,
+    }).collect();
+    actix_web::HttpResponse::Ok().json(entries)
+}
+
+async fn tags(data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let meta = data.meta();
+    let entries: Vec<TagEntry> = meta.tags.iter().map(|t| TagEntry {
+        name: &t.name,
+        url: format!("{}/tag/{}", data.config.base_url, t.name),
+        count: t.count,
+        description: &t.description,
+        aliases: &t.aliases,
+        parent: t.parent.as_deref(),
+        children: &t.children,
+    }).collect();
+    actix_web::HttpResponse::Ok().json(entries)
+}
+
+async fn projects(data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let meta = data.meta();
+    let mut entries: Vec<ProjectEntry> = meta.projects_map.values().map(|(p, html)| ProjectEntry {
+        name: &p.name,
+        title: &p.title,
+        order: p.order,
+        html,
+    }).collect();
+    entries.sort_by_key(|p| p.order);
+    actix_web::HttpResponse::Ok().json(entries)
+}
+
+/// CORS preflight, the API is read-only so only GET is allowed.
+async fn preflight() -> actix_web::HttpResponse {
+    actix_web::HttpResponse::NoContent()
+        .header("Access-Control-Allow-Methods", "GET, OPTIONS")
+        .header("Access-Control-Allow-Headers", "Content-Type")
+        .header("Access-Control-Max-Age", "86400")
+        .finish()
+}
+
+/// Versioned, read-only JSON API under `/api/v1`. Field names are part of the API and must not change within a version.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope("/api/v1")
+        .wrap(actix_web::middleware::DefaultHeaders::new().header("Access-Control-Allow-Origin", "*"))
+        .route("/articles", web::get().to(articles))
+        .route("/articles/{name}", web::get().to(article))
+        .route("/articles/{name}/comments", web::get().to(comments))
+        .route("/tags", web::get().to(tags))
+        .route("/projects", web::get().to(projects))
+        .route("/{tail:.*}", web::method(actix_web::http::Method::OPTIONS).to(preflight))
+        .default_service(web::route().to(not_found)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_past_the_end_are_empty() {
+        let items: Vec<usize> = (0..25).collect();
+        assert_eq!(page_of(&items, 1, 10), &items[..10]);
+        assert_eq!(page_of(&items, 3, 10), &items[20..]);
+        assert!(page_of(&items, 4, 10).is_empty());
+        assert!(page_of(&items, usize::MAX, MAX_PER_PAGE).is_empty());
+        assert!(page_of(&items, usize::MAX / 2, 3).is_empty());
+    }
+}
@@ -6,11 +6,11 @@ use std::time::{Duration, SystemTime};
 use std::path::Path;
 
 fn match_pattern(s: &str, pattern: &str) -> Option<String> {
-    if s.starts_with(pattern) {
-        Some(s[pattern.len()..].to_string())
-    } else {
-        None
-    }
+    s.strip_prefix(pattern).map(|rest| rest.to_string())
+}
+
+fn start(command: &str, args: &[String]) -> std::process::Child {
+    Command::new(command).args(args).spawn().expect("failed to run command")
 }
 
 fn any_older<S: AsRef<std::ffi::OsStr>>(path: &S, last_update: SystemTime) -> bool {
@@ -41,17 +41,17 @@ fn main() {
     }
 
     let mut last_update = SystemTime::now();
-    let mut child = Command::new(command.clone().unwrap()).args(args.clone()).spawn().expect("failed to run command");
+    let command = command.unwrap();
+    let mut child = start(&command, &args);
 
     loop {
-        if  dirs.iter().any(|p| any_older(p, last_update)) {
-            let _ = child.kill();
-            child = Command::new(command.clone().unwrap()).args(args.clone()).
-                spawn().expect("failed to run command");
-            std::thread::sleep(Duration::from_millis(sleep_after_restart));
-            last_update = SystemTime::now();
+        while !dirs.iter().any(|p| any_older(p, last_update)) {
+            std::thread::sleep(Duration::from_millis(200));
         }
-
-        std::thread::sleep(Duration::from_millis(200));
+        let _ = child.kill();
+        let _ = child.wait();
+        child = start(&command, &args);
+        std::thread::sleep(Duration::from_millis(sleep_after_restart));
+        last_update = SystemTime::now();
     }
-}
\ No newline at end of file
+}
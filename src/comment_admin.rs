@@ -0,0 +1,148 @@
+//! Management of published comments under `/comment_approval/a/{name}`: the admin can edit them,
+//! unpublish them back into the moderation queue or delete them. Deleted comments stay as
+//! tombstones, so `post_index` numbers, `reply_to` links and `comment_counts.toml` stay as they are.
+use actix_web::web;
+use askama::Template;
+use serde_derive::Deserialize;
+use super::{AppState, ApprovedComment, Article, CSRF_PROBLEM, LayoutTemplate, admin_session_or_new, auth_check, author_name_fn,
+    comment_markdown_to_html, csrf_ok, extract_parent_post, forget_recent_comments, html_escape, layout_template, p404,
+    sanitize_comment_html, strip_angle_brackets, unauthorized};
+
+#[derive(Template)]
+#[template(path = "comment_admin.html", escape = "none")]
+struct CommentAdminTemplate<'a> {
+    layout: LayoutTemplate<'a>,
+    article: &'a Article,
+    comments: Vec<ApprovedComment>,
+    author_name_fn: fn (&Option<String>, &str) -> String,
+    problems: Vec<String>,
+    csrf_token: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "comment_admin_edit.html")]
+struct CommentAdminEditTemplate<'a> {
+    layout: LayoutTemplate<'a>,
+    article: &'a Article,
+    comment: ApprovedComment,
+    /// The markdown to edit, or the HTML for comments approved before the markdown was kept.
+    text: String,
+    csrf_token: &'a str,
+}
+
+fn render(req: &web::HttpRequest, data: &web::Data<AppState>, article: &Article, problems: Vec<String>) -> actix_web::HttpResponse {
+    let (session, new_cookie) = admin_session_or_new(req, &data.cookie_key);
+    let tmpl = CommentAdminTemplate {
+        layout: layout_template(req, data),
+        article,
+        comments: data.comments.approved(&article.name),
+        author_name_fn,
+        problems,
+        csrf_token: &session,
+    };
+    let mut response = actix_web::HttpResponse::Ok();
+    if let Some(cookie) = new_cookie {
+        response.cookie(cookie);
+    }
+    response.body(tmpl.render().unwrap())
+}
+
+pub async fn list(req: web::HttpRequest, web::Path(name): web::Path<String>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    if !auth_check(&req, &data.admin_password) {
+        return unauthorized();
+    }
+    let meta = data.meta();
+    match meta.articles_map.get(&name) {
+        Some((article, _)) => render(&req, &data, article, Vec::new()),
+        None => p404(req, data).await,
+    }
+}
+
+pub async fn edit_form(req: web::HttpRequest, web::Path((name, index)): web::Path<(String, i64)>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    if !auth_check(&req, &data.admin_password) {
+        return unauthorized();
+    }
+    let meta = data.meta();
+    let found = meta.articles_map.get(&name).and_then(|(article, _)| data.comments.approved(&name).into_iter()
+        .find(|c| c.post_index == index && !c.deleted)
+        .map(|c| (article, c)));
+    let (article, comment) = match found {
+        Some(found) => found,
+        None => return p404(req, data).await,
+    };
+    let (session, new_cookie) = admin_session_or_new(&req, &data.cookie_key);
+    let tmpl = CommentAdminEditTemplate {
+        layout: layout_template(&req, &data),
+        article,
+        text: comment.source.clone().unwrap_or_else(|| comment.text.clone()),
+        comment,
+        csrf_token: &session,
+    };
+    let mut response = actix_web::HttpResponse::Ok();
+    if let Some(cookie) = new_cookie {
+        response.cookie(cookie);
+    }
+    response.body(tmpl.render().unwrap())
+}
+
+#[derive(Deserialize)]
+pub struct ActionForm {
+    csrf: String,
+    index: i64,
+    /// `save`, `unpublish` or `delete`.
+    action: String,
+    #[serde(default)]
+    author: String,
+    #[serde(default)]
+    website: String,
+    #[serde(default)]
+    text: String,
+}
+
+pub async fn act(req: web::HttpRequest, web::Path(name): web::Path<String>, web::Form(form): web::Form<ActionForm>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    if !auth_check(&req, &data.admin_password) {
+        return unauthorized();
+    }
+    let meta = data.meta();
+    let article = match meta.articles_map.get(&name) {
+        Some((article, _)) => article,
+        None => return p404(req, data).await,
+    };
+    if !csrf_ok(&req, &data.cookie_key, Some(&form.csrf)) {
+        return render(&req, &data, article, vec![CSRF_PROBLEM.to_string()]);
+    }
+    let gone = || vec![format!("Comment #{} is not published, it was probably deleted or unpublished already.", form.index)];
+    let problems = match form.action.as_str() {
+        "delete" | "unpublish" => {
+            let done = if form.action == "delete" {
+                data.comments.delete_approved(&name, form.index)
+            } else {
+                data.comments.unpublish(&name, form.index)
+            };
+            forget_recent_comments(&data, &name);
+            if done { Vec::new() } else { gone() }
+        },
+        "save" => {
+            let original = data.comments.approved(&name).into_iter().find(|c| c.post_index == form.index && !c.deleted);
+            match original {
+                Some(original) => {
+                    let html = match original.source {
+                        Some(_) => comment_markdown_to_html(&extract_parent_post(form.text.clone()).0),
+                        None => form.text.clone(),
+                    };
+                    let comment = ApprovedComment {
+                        author: Some(strip_angle_brackets(&form.author)).filter(|a| !a.is_empty()),
+                        website: Some(strip_angle_brackets(&form.website)).filter(|w| !w.is_empty()),
+                        text: sanitize_comment_html(&html),
+                        source: original.source.as_ref().map(|_| form.text.clone()),
+                        ..original
+                    };
+                    if data.comments.replace_approved(&name, &comment) { Vec::new() } else { gone() }
+                },
+                None => gone(),
+            }
+        },
+        other => vec![format!("Unknown action \"{}\", nothing was changed.", html_escape(other))],
+    };
+    render(&req, &data, article, problems)
+}
@@ -0,0 +1,183 @@
+//! Lets commenters change their mind: `comment` adds every submission to a signed `comment_edits`
+//! cookie, which allows editing or deleting the comment while it waits for moderation and for
+//! `comment_edit_minutes` after it is approved. Edits of published comments are moderated again,
+//! deleting one leaves a tombstone like deleting it from the admin UI does.
+use actix_web::{HttpMessage, web};
+use askama::Template;
+use serde_derive::Deserialize;
+use super::{AppState, ApprovedComment, Article, Config, LayoutTemplate, Meta, UncheckedComment, error, find_article, forget_recent_comments,
+    is_unlocked, layout_template, p404, request_messages, tripcode};
+
+const COOKIE: &str = "comment_edits";
+/// How many submissions the cookie remembers, the oldest are forgotten first.
+const MAX_OWNED: usize = 20;
+
+/// The edit cookie holding `ids`, or one that removes the cookie when there are none.
+fn cookie_with(ids: &[String], key: &actix_web::cookie::Key) -> actix_web::cookie::Cookie<'static> {
+    let mut jar = actix_web::cookie::CookieJar::new();
+    jar.signed(key).add(
+        actix_web::cookie::Cookie::build(COOKIE, ids.join("."))
+            .path("/")
+            .http_only(true)
+            .same_site(actix_web::cookie::SameSite::Lax)
+            .max_age(if ids.is_empty() { time::Duration::zero() } else { time::Duration::days(30) })
+            .finish());
+    jar.get(COOKIE).unwrap().clone()
+}
+
+/// The edit cookie after a new submission, the same cookie keeps all of the reader's submissions.
+pub fn cookie(req: &web::HttpRequest, id: &str, key: &actix_web::cookie::Key) -> actix_web::cookie::Cookie<'static> {
+    let mut ids = owned_ids(req, key);
+    ids.retain(|owned| owned != id);
+    ids.insert(0, id.to_string());
+    ids.truncate(MAX_OWNED);
+    cookie_with(&ids, key)
+}
+
+/// Submission IDs from the reader's edit cookie, newest first.
+pub fn owned_ids(req: &web::HttpRequest, key: &actix_web::cookie::Key) -> Vec<String> {
+    let cookie = match req.cookie(COOKIE) {
+        Some(cookie) => cookie,
+        None => return Vec::new(),
+    };
+    let mut jar = actix_web::cookie::CookieJar::new();
+    jar.add_original(cookie);
+    let signed = jar.signed(key).get(COOKIE);
+    signed.map_or_else(Vec::new, |c| c.value().split('.').filter(|id| !id.is_empty()).map(str::to_string).collect())
+}
+
+pub fn within_window(comment: &ApprovedComment, config: &Config) -> bool {
+    let now = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    comment.approved.is_some_and(|approved| now < approved + config.comment_edit_minutes * 60)
+}
+
+/// The reader's own comments on `article` that wait for moderation.
+pub fn pending(req: &web::HttpRequest, data: &AppState, article: &str) -> Vec<String> {
+    let owned = owned_ids(req, &data.cookie_key);
+    if owned.is_empty() {
+        return Vec::new();
+    }
+    data.comments.unchecked().into_iter()
+        .filter(|c| c.article == article && owned.contains(&c.id))
+        .map(|c| c.id)
+        .collect()
+}
+
+enum Editable {
+    /// A new comment or an edit of a published one, waiting for moderation.
+    Queued(UncheckedComment),
+    Published(ApprovedComment),
+}
+
+fn find<'a>(req: &web::HttpRequest, data: &AppState, meta: &'a Meta, name: &str, id: &str) -> Option<(&'a Article, Editable)> {
+    let (article, _) = find_article(req, data, meta, name).filter(|(a, _)| !a.draft && is_unlocked(req, &data.cookie_key, a))?;
+    if !owned_ids(req, &data.cookie_key).iter().any(|owned| owned == id) {
+        return None;
+    }
+    if let Some(queued) = data.comments.unchecked().into_iter().find(|c| c.id == id && c.article == name) {
+        return Some((article, Editable::Queued(queued)));
+    }
+    data.comments.approved(name).into_iter()
+        .find(|c| c.submission.as_deref() == Some(id) && !c.deleted && within_window(c, &data.config))
+        .map(|c| (article, Editable::Published(c)))
+}
+
+#[derive(Template)]
+#[template(path = "comment_edit.html")]
+struct CommentEditTemplate<'a> {
+    layout: LayoutTemplate<'a>,
+    article: &'a Article,
+    author: String,
+    website: String,
+    text: String,
+    published: bool,
+}
+
+pub async fn form(req: web::HttpRequest, web::Path((name, id)): web::Path<(String, String)>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let meta = data.meta();
+    let (article, editable) = match find(&req, &data, &meta, &name, &id) {
+        Some(found) => found,
+        None => return p404(req, data).await,
+    };
+    let (author, website, text, published) = match editable {
+        Editable::Queued(c) => (c.author, c.website, c.text, c.edit_of.is_some()),
+        Editable::Published(c) => (c.author, c.website, c.source.unwrap_or_default(), true),
+    };
+    let tmpl = CommentEditTemplate {
+        layout: layout_template(&req, &data),
+        article,
+        author: author.unwrap_or_default(),
+        website: website.unwrap_or_default(),
+        text,
+        published,
+    };
+    actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
+}
+
+#[derive(Deserialize)]
+pub struct EditForm {
+    author: String,
+    website: String,
+    text: String,
+    /// `save` or `delete`.
+    action: String,
+}
+
+pub async fn save(req: web::HttpRequest, web::Path((name, id)): web::Path<(String, String)>, web::Form(form): web::Form<EditForm>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let meta = data.meta();
+    let editable = match find(&req, &data, &meta, &name, &id) {
+        Some((_, editable)) => editable,
+        None => return p404(req, data).await,
+    };
+    let back = || actix_web::HttpResponse::Found().header(actix_web::http::header::LOCATION, format!("/a/{}", name)).finish();
+
+    if form.action == "delete" {
+        let published = match editable {
+            Editable::Queued(c) => {
+                data.comments.withdraw(&c.id);
+                c.edit_of.and_then(|post_index| data.comments.approved(&name).into_iter().find(|a| a.post_index == post_index))
+                    .filter(|a| within_window(a, &data.config))
+            },
+            Editable::Published(c) => {
+                data.comments.withdraw(&id);
+                Some(c)
+            },
+        };
+        if let Some(c) = published {
+            data.comments.delete_approved(&name, c.post_index);
+            forget_recent_comments(&data, &name);
+        }
+        let mut owned = owned_ids(&req, &data.cookie_key);
+        owned.retain(|owned| *owned != id);
+        let mut response = back();
+        response.add_cookie(&cookie_with(&owned, &data.cookie_key)).unwrap();
+        return response;
+    }
+
+    if form.author.len() > 100 || form.text.len() > 10000 || form.website.len() > 500 {
+        return error(&req, &request_messages(&req, &data).comment_too_long, &data).await;
+    }
+    if form.text.is_empty() {
+        return error(&req, &request_messages(&req, &data).comment_empty, &data).await;
+    }
+    let (author, tripcode) = tripcode::split(&form.author, &data.tripcode_key);
+    let (timestamp, edit_of, previous_tripcode) = match editable {
+        Editable::Queued(c) => (c.timestamp, c.edit_of, c.tripcode),
+        Editable::Published(c) => {
+            let now = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            (now, Some(c.post_index), c.tripcode)
+        },
+    };
+    data.comments.resubmit(UncheckedComment {
+        id: id.clone(),
+        timestamp,
+        author: if author.is_empty() { None } else { Some(author) },
+        // Editing without the secret keeps the tripcode, the cookie already shows who wrote it.
+        tripcode: tripcode.or(previous_tripcode),
+        website: if form.website.is_empty() { None } else { Some(form.website) },
+        text: form.text,
+        article: name.clone(),
+        edit_of,
+    });
+    back()
+}
@@ -0,0 +1,915 @@
+use rusqlite::{params, OptionalExtension};
+use serde_derive::Deserialize;
+use std::sync::{Arc, Mutex};
+use super::{ApprovedComment, ApprovedComments, UncheckedComment, UncheckedComments, append_to_file, blog_data_dir,
+    comments_database_path, read_toml, read_toml_default};
+
+/// Which `CommentStore` the blog uses, `comment_store` in the config.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommentStoreKind {
+    /// The original files under `blog_data_dir`.
+    #[default]
+    Toml,
+    /// `comments.sqlite` under `blog_data_dir`, filled from the TOML files with `blog --import-comments`.
+    Sqlite,
+}
+
+/// What the moderator chose for one queued comment, comments left alone stay queued.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Decision {
+    Approve,
+    Delete,
+}
+
+/// Outcome of `CommentStore::moderate`.
+#[derive(Default)]
+pub struct Moderation {
+    /// Articles of the approved comments, in approval order.
+    pub articles: Vec<String>,
+    /// IDs that were not in the queue, e.g. already moderated in another tab.
+    pub missing: Vec<String>,
+}
+
+/// Turns a queued comment into its published form once it has a `post_index`.
+pub type Publish = fn(&UncheckedComment, i64) -> ApprovedComment;
+
+pub trait CommentStore: Send + Sync {
+    /// Queues a new comment for moderation, giving it a new `id`, which is returned.
+    fn submit(&self, comment: UncheckedComment) -> String;
+
+    /// Replaces the queued comment with the same `id`, or queues it with that `id` when there is none.
+    fn resubmit(&self, comment: UncheckedComment);
+
+    /// Removes a comment from the queue without moderation, returns false if it wasn't there.
+    fn withdraw(&self, id: &str) -> bool;
+
+    /// The moderation queue, oldest first.
+    fn unchecked(&self) -> Vec<UncheckedComment>;
+
+    /// Applies `decisions` to the queued comments with the given IDs. Approved comments get the next
+    /// `post_index` of their article, approved edits replace the comment they edit.
+    fn moderate(&self, decisions: &[(String, Decision)], publish: Publish) -> Moderation;
+
+    /// Published comments of an article, by `post_index`.
+    fn approved(&self, article: &str) -> Vec<ApprovedComment>;
+
+    /// Runs `update` on every published comment, saving the ones it returns true for. Returns how
+    /// many were changed.
+    fn update_approved(&self, update: &dyn Fn(&mut ApprovedComment) -> bool) -> usize;
+
+    /// Saves a changed published comment over the one with the same `post_index`, returns false if
+    /// there was none or it has been deleted in the meantime.
+    fn replace_approved(&self, article: &str, comment: &ApprovedComment) -> bool;
+
+    /// Turns a published comment into a tombstone and drops its queued edits, returns false if it
+    /// wasn't there.
+    fn delete_approved(&self, article: &str, post_index: i64) -> bool;
+
+    /// Like `delete_approved`, but queues the comment again as an edit of its tombstone, so
+    /// approving it puts the comment back in its place.
+    fn unpublish(&self, article: &str, post_index: i64) -> bool;
+
+    /// Published comments signed with a tripcode, with their articles.
+    fn by_tripcode(&self, tripcode: &str) -> Vec<(String, ApprovedComment)>;
+}
+
+pub fn open(kind: CommentStoreKind) -> Arc<dyn CommentStore> {
+    match kind {
+        CommentStoreKind::Toml => Arc::new(TomlStore::open(&blog_data_dir())),
+        CommentStoreKind::Sqlite => Arc::new(SqliteStore::open(&comments_database_path())),
+    }
+}
+
+/// An approved edit keeps the place, thread and dates of the comment it edits. Approving an
+/// unpublished comment restores its tombstone without marking it edited.
+fn merge_edit(original: &ApprovedComment, mut edit: ApprovedComment) -> ApprovedComment {
+    edit.timestamp = original.timestamp;
+    edit.post_index = original.post_index;
+    edit.reply_to = original.reply_to;
+    edit.submission = original.submission.clone();
+    edit.edited = if original.deleted { original.edited } else { edit.approved };
+    edit.approved = original.approved;
+    edit
+}
+
+/// What is left of a deleted comment. It keeps its number and place in the thread, so replies
+/// and `>>N` references to it still make sense.
+fn tombstone(c: &ApprovedComment) -> ApprovedComment {
+    ApprovedComment {
+        timestamp: c.timestamp,
+        post_index: c.post_index,
+        reply_to: c.reply_to,
+        submission: c.submission.clone(),
+        approved: c.approved,
+        edited: c.edited,
+        deleted: true,
+        ..Default::default()
+    }
+}
+
+/// An unpublished comment back in the queue, as an edit of its own tombstone.
+fn requeued(article: &str, c: &ApprovedComment) -> UncheckedComment {
+    UncheckedComment {
+        id: String::new(),
+        timestamp: c.timestamp,
+        author: c.author.clone(),
+        tripcode: c.tripcode.clone(),
+        website: c.website.clone(),
+        // Comments approved before the source was kept only have their HTML.
+        text: c.source.clone().unwrap_or_else(|| c.text.clone()),
+        article: article.to_string(),
+        edit_of: Some(c.post_index),
+    }
+}
+
+pub struct TomlStore {
+    lock: Mutex<()>,
+    /// The blog data directory holding the files.
+    dir: std::path::PathBuf,
+}
+
+fn new_comment_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Increments the article's count in `comment_counts.toml` and returns it, the first comment gets 0.
+fn fetch_incr_count(value: &mut toml::Value, key: &str) -> i64 {
+    match value {
+        toml::Value::Table(t) => {
+            match t.get_mut(key) {
+                Some(v) => {
+                    if let toml::Value::Integer(res) = v {
+                        *res += 1;
+                        *res
+                    } else {
+                        panic!("count was not integer");
+                    }
+                },
+                None => {
+                    t.insert(key.to_string(), toml::Value::Integer(0));
+                    0
+                }
+            }
+        },
+        _ => panic!("unexpected"),
+    }
+}
+
+impl TomlStore {
+    pub fn open(dir: &str) -> TomlStore {
+        TomlStore { lock: Mutex::new(()), dir: dir.into() }
+    }
+
+    fn queue_path(&self) -> String {
+        self.dir.join("unverified_comments.toml").to_string_lossy().to_string()
+    }
+
+    fn counts_path(&self) -> String {
+        self.dir.join("comment_counts.toml").to_string_lossy().to_string()
+    }
+
+    fn comments_dir(&self) -> String {
+        self.dir.join("comments").to_string_lossy().to_string()
+    }
+
+    fn comments_path(&self, article: &str) -> String {
+        self.dir.join("comments").join(format!("{}.toml", article)).to_string_lossy().to_string()
+    }
+
+    /// Reads `unverified_comments.toml`, giving IDs to comments queued before there were IDs.
+    /// Call with the store lock held.
+    fn read_queue(&self) -> Vec<UncheckedComment> {
+        let mut queue = read_toml_default::<UncheckedComments>(&self.queue_path());
+        if queue.comments.iter().any(|c| c.id.is_empty()) {
+            for c in queue.comments.iter_mut().filter(|c| c.id.is_empty()) {
+                c.id = new_comment_id();
+            }
+            self.write_queue(&queue);
+        }
+        queue.comments
+    }
+
+    fn write_queue(&self, queue: &UncheckedComments) {
+        if queue.comments.is_empty() {
+            std::fs::write(self.queue_path(), "").unwrap();
+        } else {
+            std::fs::write(self.queue_path(), toml::to_string(queue).unwrap()).unwrap();
+        }
+    }
+
+    /// Tombstones a published comment and drops its queued edits, returning the comment as it was.
+    /// Call with the store lock held.
+    fn remove_approved(&self, article: &str, post_index: i64) -> Option<ApprovedComment> {
+        let removed = self.change_approved(article, |comments| comments.iter_mut()
+            .find(|c| c.post_index == post_index && !c.deleted)
+            .map(|c| {
+                let dead = tombstone(c);
+                std::mem::replace(c, dead)
+            }))?;
+        let mut queue = UncheckedComments { comments: self.read_queue() };
+        queue.comments.retain(|c| !(c.article == article && c.edit_of == Some(post_index)));
+        self.write_queue(&queue);
+        Some(removed)
+    }
+
+    /// Rewrites an article's comment file with `change`. Call with the store lock held.
+    fn change_approved<T>(&self, article: &str, change: impl FnOnce(&mut Vec<ApprovedComment>) -> T) -> T {
+        let mut comments = read_toml_default::<ApprovedComments>(&self.comments_path(article));
+        let result = change(&mut comments.comments);
+        std::fs::write(self.comments_path(article), toml::to_string(&comments).unwrap()).unwrap();
+        result
+    }
+}
+
+impl CommentStore for TomlStore {
+    fn submit(&self, mut comment: UncheckedComment) -> String {
+        comment.id = new_comment_id();
+        let id = comment.id.clone();
+        let _lock_guard = self.lock.lock().unwrap();
+        append_to_file(&self.queue_path(), &toml::to_string(&UncheckedComments { comments: vec![comment] }).unwrap());
+        id
+    }
+
+    fn resubmit(&self, comment: UncheckedComment) {
+        let _lock_guard = self.lock.lock().unwrap();
+        let mut queue = UncheckedComments { comments: self.read_queue() };
+        match queue.comments.iter_mut().find(|c| c.id == comment.id) {
+            Some(queued) => *queued = comment,
+            None => queue.comments.push(comment),
+        }
+        self.write_queue(&queue);
+    }
+
+    fn withdraw(&self, id: &str) -> bool {
+        let _lock_guard = self.lock.lock().unwrap();
+        let mut queue = UncheckedComments { comments: self.read_queue() };
+        let before = queue.comments.len();
+        queue.comments.retain(|c| c.id != id);
+        self.write_queue(&queue);
+        queue.comments.len() != before
+    }
+
+    fn unchecked(&self) -> Vec<UncheckedComment> {
+        let _lock_guard = self.lock.lock().unwrap();
+        self.read_queue()
+    }
+
+    fn moderate(&self, decisions: &[(String, Decision)], publish: Publish) -> Moderation {
+        let mut approved_comments: Vec<(String, ApprovedComments)> = Vec::new();
+        let mut moderation = Moderation::default();
+        {
+            let _lock_guard = self.lock.lock().unwrap();
+            let mut queue = UncheckedComments { comments: self.read_queue() };
+            let mut comment_counts: toml::Value = read_toml(&self.counts_path());
+
+            for (id, decision) in decisions {
+                let c = match queue.comments.iter().position(|c| &c.id == id) {
+                    Some(i) => queue.comments.remove(i),
+                    None => {
+                        moderation.missing.push(id.clone());
+                        continue;
+                    }
+                };
+                match (decision, c.edit_of) {
+                    (Decision::Delete, _) => (),
+                    (Decision::Approve, Some(post_index)) => {
+                        let edit = publish(&c, post_index);
+                        let found = self.change_approved(&c.article, |comments| match comments.iter_mut().find(|a| a.post_index == post_index) {
+                            Some(original) => {
+                                *original = merge_edit(original, edit);
+                                true
+                            },
+                            None => false,
+                        });
+                        if !found {
+                            moderation.missing.push(id.clone());
+                        }
+                    },
+                    (Decision::Approve, None) => approved_comments.push((c.article.clone(),
+                        ApprovedComments { comments: vec![publish(&c, fetch_incr_count(&mut comment_counts, &c.article))] })),
+                }
+            }
+            self.write_queue(&queue);
+            std::fs::write(self.counts_path(), toml::to_string(&comment_counts).unwrap()).unwrap();
+            std::fs::create_dir_all(self.comments_dir()).unwrap();
+            for (article, comments) in approved_comments {
+                append_to_file(&self.comments_path(&article), &toml::to_string(&comments).unwrap());
+                moderation.articles.push(article);
+            }
+        }
+        moderation
+    }
+
+    fn approved(&self, article: &str) -> Vec<ApprovedComment> {
+        read_toml_default::<ApprovedComments>(&self.comments_path(article)).comments
+    }
+
+    fn update_approved(&self, update: &dyn Fn(&mut ApprovedComment) -> bool) -> usize {
+        let _lock_guard = self.lock.lock().unwrap();
+        let mut changed = 0;
+        let dir = match std::fs::read_dir(self.comments_dir()) {
+            Ok(dir) => dir,
+            Err(_) => return 0,
+        };
+        for entry in dir {
+            let path = entry.unwrap().path().to_string_lossy().to_string();
+            let mut comments = read_toml::<ApprovedComments>(&path);
+            let changed_here = comments.comments.iter_mut().map(update).filter(|&c| c).count();
+            if changed_here > 0 {
+                std::fs::write(&path, toml::to_string(&comments).unwrap()).unwrap();
+                changed += changed_here;
+            }
+        }
+        changed
+    }
+
+    fn replace_approved(&self, article: &str, comment: &ApprovedComment) -> bool {
+        let _lock_guard = self.lock.lock().unwrap();
+        self.change_approved(article, |comments| match comments.iter_mut().find(|c| c.post_index == comment.post_index && !c.deleted) {
+            Some(c) => {
+                *c = comment.clone();
+                true
+            },
+            None => false,
+        })
+    }
+
+    fn delete_approved(&self, article: &str, post_index: i64) -> bool {
+        let _lock_guard = self.lock.lock().unwrap();
+        self.remove_approved(article, post_index).is_some()
+    }
+
+    fn unpublish(&self, article: &str, post_index: i64) -> bool {
+        let _lock_guard = self.lock.lock().unwrap();
+        match self.remove_approved(article, post_index) {
+            Some(c) => {
+                let mut queue = UncheckedComments { comments: self.read_queue() };
+                queue.comments.push(UncheckedComment { id: new_comment_id(), ..requeued(article, &c) });
+                self.write_queue(&queue);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn by_tripcode(&self, tripcode: &str) -> Vec<(String, ApprovedComment)> {
+        let dir = match std::fs::read_dir(self.comments_dir()) {
+            Ok(dir) => dir,
+            Err(_) => return Vec::new(),
+        };
+        let mut found = Vec::new();
+        for entry in dir {
+            let path = entry.unwrap().path();
+            let article = match path.file_stem() {
+                Some(stem) => stem.to_string_lossy().to_string(),
+                None => continue,
+            };
+            found.extend(read_toml::<ApprovedComments>(&path.to_string_lossy()).comments.into_iter()
+                .filter(|c| c.tripcode.as_deref() == Some(tripcode))
+                .map(|c| (article.clone(), c)));
+        }
+        found
+    }
+}
+
+pub struct SqliteStore {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS unchecked_comments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    article TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    author TEXT,
+    website TEXT,
+    text TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS comments (
+    article TEXT NOT NULL,
+    post_index INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    author TEXT,
+    website TEXT,
+    text TEXT NOT NULL,
+    reply_to INTEGER,
+    PRIMARY KEY (article, post_index)
+);
+-- The last post_index given out per article, like comment_counts.toml.
+CREATE TABLE IF NOT EXISTS comment_counts (
+    article TEXT PRIMARY KEY,
+    count INTEGER NOT NULL
+);
+";
+
+/// Changes to `SCHEMA`, the database's `user_version` is how many have been applied.
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE unchecked_comments ADD COLUMN tripcode TEXT;
+     ALTER TABLE comments ADD COLUMN tripcode TEXT;",
+    "ALTER TABLE unchecked_comments ADD COLUMN edit_of INTEGER;
+     ALTER TABLE comments ADD COLUMN source TEXT;
+     ALTER TABLE comments ADD COLUMN submission TEXT;
+     ALTER TABLE comments ADD COLUMN approved INTEGER;
+     ALTER TABLE comments ADD COLUMN edited INTEGER;",
+    "ALTER TABLE comments ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;",
+    // Queued comments imported from TOML keep their IDs, commenters' edit cookies hold them.
+    "ALTER TABLE unchecked_comments ADD COLUMN imported_id TEXT;",
+];
+
+/// The columns read by `unchecked_row`, in order.
+const UNCHECKED_COLUMNS: &str = "COALESCE(imported_id, CAST(id AS TEXT)), article, timestamp, author, website, text, tripcode, edit_of";
+/// The columns read by `approved_row`, in order, followed by `article`.
+const APPROVED_COLUMNS: &str = "timestamp, author, website, text, post_index, reply_to, tripcode, source, submission, approved, edited, deleted, article";
+
+fn unchecked_row(row: &rusqlite::Row) -> rusqlite::Result<UncheckedComment> {
+    Ok(UncheckedComment {
+        id: row.get(0)?,
+        article: row.get(1)?,
+        timestamp: row.get::<_, i64>(2)? as u64,
+        author: row.get(3)?,
+        website: row.get(4)?,
+        text: row.get(5)?,
+        tripcode: row.get(6)?,
+        edit_of: row.get(7)?,
+    })
+}
+
+fn approved_row(row: &rusqlite::Row) -> rusqlite::Result<ApprovedComment> {
+    Ok(ApprovedComment {
+        timestamp: row.get::<_, i64>(0)? as u64,
+        author: row.get(1)?,
+        website: row.get(2)?,
+        text: row.get(3)?,
+        post_index: row.get(4)?,
+        reply_to: row.get(5)?,
+        tripcode: row.get(6)?,
+        source: row.get(7)?,
+        submission: row.get(8)?,
+        approved: row.get::<_, Option<i64>>(9)?.map(|t| t as u64),
+        edited: row.get::<_, Option<i64>>(10)?.map(|t| t as u64),
+        deleted: row.get(11)?,
+    })
+}
+
+/// The row and imported ID of a queued comment. Its `id` is the row number, or the ID it had in the TOML queue.
+fn queued_row(connection: &rusqlite::Connection, id: &str) -> rusqlite::Result<Option<(i64, Option<String>)>> {
+    connection.query_row("SELECT id, imported_id FROM unchecked_comments WHERE imported_id = ?1 OR (imported_id IS NULL AND id = ?2)",
+        params![id, id.parse::<i64>().ok()], |r| Ok((r.get(0)?, r.get(1)?))).optional()
+}
+
+/// Queues `c` in the given row, or in a new one, and returns its `id`.
+fn insert_unchecked_row(connection: &rusqlite::Connection, row: Option<i64>, imported_id: Option<String>, c: &UncheckedComment) -> rusqlite::Result<String> {
+    connection.execute("INSERT OR REPLACE INTO unchecked_comments (id, imported_id, article, timestamp, author, website, text, tripcode, edit_of)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        params![row, imported_id, c.article, c.timestamp as i64, c.author, c.website, c.text, c.tripcode, c.edit_of])?;
+    Ok(imported_id.unwrap_or_else(|| connection.last_insert_rowid().to_string()))
+}
+
+/// Queues `c`, with its own `id` when it has one.
+fn insert_unchecked(connection: &rusqlite::Connection, c: &UncheckedComment) -> rusqlite::Result<String> {
+    let (row, imported_id) = match queued_row(connection, &c.id)? {
+        Some((row, imported_id)) => (Some(row), imported_id),
+        None if c.id.is_empty() => (None, None),
+        None => match c.id.parse::<i64>() {
+            Ok(row) => (Some(row), None),
+            Err(_) => (None, Some(c.id.clone())),
+        },
+    };
+    insert_unchecked_row(connection, row, imported_id, c)
+}
+
+/// Inserts `c`, or replaces the comment with the same `post_index`.
+fn save_approved(connection: &rusqlite::Connection, article: &str, c: &ApprovedComment) -> rusqlite::Result<()> {
+    connection.execute("INSERT OR REPLACE INTO comments (article, post_index, timestamp, author, website, text, reply_to, tripcode, source, submission, approved, edited, deleted)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        params![article, c.post_index, c.timestamp as i64, c.author, c.website, c.text, c.reply_to, c.tripcode, c.source, c.submission,
+            c.approved.map(|t| t as i64), c.edited.map(|t| t as i64), c.deleted])?;
+    Ok(())
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> SqliteStore {
+        let connection = rusqlite::Connection::open(path).unwrap_or_else(|e| panic!("Failed to open comment database {}: {}", path, e));
+        connection.busy_timeout(std::time::Duration::from_secs(5)).unwrap();
+        connection.execute_batch(SCHEMA).expect("Failed to create comment tables");
+        let version: usize = connection.query_row("PRAGMA user_version", params![], |r| r.get::<_, i64>(0)).unwrap() as usize;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            connection.execute_batch(&format!("BEGIN; {} PRAGMA user_version = {}; COMMIT;", migration, i + 1))
+                .expect("Failed to migrate the comment database");
+        }
+        SqliteStore { connection: Mutex::new(connection) }
+    }
+
+    /// Copies the TOML comment files in `dir` into an empty database in one transaction, returning how many
+    /// queued and published comments were imported. The files are left as they are.
+    pub fn import_toml(&self, dir: &str) -> rusqlite::Result<(usize, usize)> {
+        let files = TomlStore::open(dir);
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        let existing: i64 = tx.query_row("SELECT (SELECT COUNT(*) FROM comments) + (SELECT COUNT(*) FROM unchecked_comments)", params![], |r| r.get(0))?;
+        assert!(existing == 0, "The comment database already has comments, not importing again");
+
+        let unchecked = read_toml_default::<UncheckedComments>(&files.queue_path()).comments;
+        for c in &unchecked {
+            // Comments queued before there were IDs get new ones.
+            insert_unchecked_row(&tx, None, Some(c.id.clone()).filter(|id| !id.is_empty()), c)?;
+        }
+        let mut approved = 0;
+        if let Ok(dir) = std::fs::read_dir(files.comments_dir()) {
+            for entry in dir {
+                let path = entry.unwrap().path();
+                let article = match (path.file_stem(), path.extension()) {
+                    (Some(stem), Some(ext)) if ext == "toml" => stem.to_string_lossy().to_string(),
+                    _ => continue,
+                };
+                for c in read_toml::<ApprovedComments>(&path.to_string_lossy()).comments {
+                    save_approved(&tx, &article, &c)?;
+                    approved += 1;
+                }
+            }
+        }
+        if std::path::Path::new(&files.counts_path()).exists() {
+            if let toml::Value::Table(counts) = read_toml::<toml::Value>(&files.counts_path()) {
+                for (article, count) in counts {
+                    tx.execute("INSERT INTO comment_counts (article, count) VALUES (?, ?)", params![article, count.as_integer()])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok((unchecked.len(), approved))
+    }
+
+    /// Tombstones a published comment and drops its queued edits, queueing the comment again when
+    /// `requeue` is set. Returns false if there was no such comment.
+    fn remove_approved(&self, article: &str, post_index: i64, requeue: bool) -> bool {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate).unwrap();
+        let c = match tx.query_row(&format!("SELECT {} FROM comments WHERE article = ? AND post_index = ? AND NOT deleted", APPROVED_COLUMNS),
+            params![article, post_index], approved_row).optional().unwrap() {
+            Some(c) => c,
+            None => return false,
+        };
+        save_approved(&tx, article, &tombstone(&c)).unwrap();
+        tx.execute("DELETE FROM unchecked_comments WHERE article = ? AND edit_of = ?", params![article, post_index]).unwrap();
+        if requeue {
+            insert_unchecked(&tx, &requeued(article, &c)).unwrap();
+        }
+        tx.commit().unwrap();
+        true
+    }
+}
+
+impl CommentStore for SqliteStore {
+    fn submit(&self, c: UncheckedComment) -> String {
+        insert_unchecked(&self.connection.lock().unwrap(), &UncheckedComment { id: String::new(), ..c }).unwrap()
+    }
+
+    fn resubmit(&self, c: UncheckedComment) {
+        insert_unchecked(&self.connection.lock().unwrap(), &c).unwrap();
+    }
+
+    fn withdraw(&self, id: &str) -> bool {
+        let connection = self.connection.lock().unwrap();
+        match queued_row(&connection, id).unwrap() {
+            Some((row, _)) => connection.execute("DELETE FROM unchecked_comments WHERE id = ?", params![row]).unwrap() > 0,
+            None => false,
+        }
+    }
+
+    fn unchecked(&self) -> Vec<UncheckedComment> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!("SELECT {} FROM unchecked_comments ORDER BY id", UNCHECKED_COLUMNS)).unwrap();
+        let rows = statement.query_map(params![], unchecked_row).unwrap();
+        rows.map(Result::unwrap).collect()
+    }
+
+    fn moderate(&self, decisions: &[(String, Decision)], publish: Publish) -> Moderation {
+        let mut connection = self.connection.lock().unwrap();
+        // Immediate, so that a comment can't be moderated twice by another process in between.
+        let tx = connection.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate).unwrap();
+        let mut moderation = Moderation::default();
+        for (id, decision) in decisions {
+            let (row, c) = match queued_row(&tx, id).unwrap().map(|(row, _)| (row, tx.query_row(
+                &format!("SELECT {} FROM unchecked_comments WHERE id = ?", UNCHECKED_COLUMNS),
+                params![row], unchecked_row).unwrap())) {
+                Some(found) => found,
+                None => {
+                    moderation.missing.push(id.clone());
+                    continue;
+                }
+            };
+            match (decision, c.edit_of) {
+                (Decision::Delete, _) => (),
+                (Decision::Approve, Some(post_index)) => {
+                    let original = tx.query_row(&format!("SELECT {} FROM comments WHERE article = ? AND post_index = ?", APPROVED_COLUMNS),
+                        params![c.article, post_index], approved_row).optional().unwrap();
+                    match original {
+                        Some(original) => save_approved(&tx, &c.article, &merge_edit(&original, publish(&c, post_index))).unwrap(),
+                        None => moderation.missing.push(id.clone()),
+                    }
+                },
+                (Decision::Approve, None) => {
+                    tx.execute("INSERT INTO comment_counts (article, count) VALUES (?, 0) ON CONFLICT (article) DO UPDATE SET count = count + 1",
+                        params![c.article]).unwrap();
+                    let post_index: i64 = tx.query_row("SELECT count FROM comment_counts WHERE article = ?", params![c.article], |r| r.get(0)).unwrap();
+                    save_approved(&tx, &c.article, &publish(&c, post_index)).unwrap();
+                    moderation.articles.push(c.article.clone());
+                },
+            }
+            tx.execute("DELETE FROM unchecked_comments WHERE id = ?", params![row]).unwrap();
+        }
+        tx.commit().unwrap();
+        moderation
+    }
+
+    fn approved(&self, article: &str) -> Vec<ApprovedComment> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!("SELECT {} FROM comments WHERE article = ? ORDER BY post_index", APPROVED_COLUMNS)).unwrap();
+        let rows = statement.query_map(params![article], approved_row).unwrap();
+        rows.map(Result::unwrap).collect()
+    }
+
+    fn update_approved(&self, update: &dyn Fn(&mut ApprovedComment) -> bool) -> usize {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate).unwrap();
+        let comments: Vec<(String, ApprovedComment)> = {
+            let mut statement = tx.prepare(&format!("SELECT {} FROM comments", APPROVED_COLUMNS)).unwrap();
+            let rows = statement.query_map(params![], |row| Ok((row.get(12)?, approved_row(row)?))).unwrap();
+            rows.map(Result::unwrap).collect()
+        };
+        let mut changed = 0;
+        for (article, mut c) in comments {
+            if update(&mut c) {
+                save_approved(&tx, &article, &c).unwrap();
+                changed += 1;
+            }
+        }
+        tx.commit().unwrap();
+        changed
+    }
+
+    fn replace_approved(&self, article: &str, comment: &ApprovedComment) -> bool {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate).unwrap();
+        let exists = tx.query_row("SELECT 1 FROM comments WHERE article = ? AND post_index = ? AND NOT deleted", params![article, comment.post_index], |_| Ok(()))
+            .optional().unwrap().is_some();
+        if exists {
+            save_approved(&tx, article, comment).unwrap();
+        }
+        tx.commit().unwrap();
+        exists
+    }
+
+    fn delete_approved(&self, article: &str, post_index: i64) -> bool {
+        self.remove_approved(article, post_index, false)
+    }
+
+    fn unpublish(&self, article: &str, post_index: i64) -> bool {
+        self.remove_approved(article, post_index, true)
+    }
+
+    fn by_tripcode(&self, tripcode: &str) -> Vec<(String, ApprovedComment)> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!("SELECT {} FROM comments WHERE tripcode = ?", APPROVED_COLUMNS)).unwrap();
+        let rows = statement.query_map(params![tripcode], |row| Ok((row.get(12)?, approved_row(row)?))).unwrap();
+        rows.map(Result::unwrap).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish(c: &UncheckedComment, post_index: i64) -> ApprovedComment {
+        ApprovedComment {
+            timestamp: c.timestamp,
+            author: c.author.clone(),
+            text: format!("<p>{}</p>", c.text),
+            source: Some(c.text.clone()),
+            submission: Some(c.id.clone()),
+            post_index,
+            approved: Some(c.timestamp + 60),
+            ..Default::default()
+        }
+    }
+
+    fn comment(article: &str, text: &str, edit_of: Option<i64>) -> UncheckedComment {
+        UncheckedComment {
+            timestamp: 1000,
+            author: Some("Reader".to_string()),
+            text: text.to_string(),
+            article: article.to_string(),
+            edit_of,
+            ..Default::default()
+        }
+    }
+
+    fn published() -> ApprovedComment {
+        ApprovedComment {
+            timestamp: 1000,
+            author: Some("Reader".to_string()),
+            tripcode: Some("AAAAAAAAAAAA".to_string()),
+            website: Some("https://reader.example".to_string()),
+            text: "<p>original</p>".to_string(),
+            post_index: 3,
+            reply_to: Some(1),
+            source: Some("original".to_string()),
+            submission: Some("cookie".to_string()),
+            approved: Some(1060),
+            edited: None,
+            deleted: false,
+        }
+    }
+
+    #[test]
+    fn merged_edit_keeps_place_and_dates() {
+        let edit = ApprovedComment { timestamp: 2000, approved: Some(2060), reply_to: None, ..publish(&comment("a", "edited", Some(3)), 3) };
+        let merged = merge_edit(&published(), edit);
+        assert_eq!((merged.timestamp, merged.post_index, merged.reply_to), (1000, 3, Some(1)));
+        assert_eq!((merged.approved, merged.edited), (Some(1060), Some(2060)));
+        assert_eq!(merged.submission.as_deref(), Some("cookie"));
+        assert_eq!(merged.text, "<p>edited</p>");
+        assert!(!merged.deleted);
+    }
+
+    #[test]
+    fn tombstone_keeps_only_the_place() {
+        let dead = tombstone(&ApprovedComment { edited: Some(1500), ..published() });
+        assert!(dead.deleted);
+        assert_eq!((dead.timestamp, dead.post_index, dead.reply_to), (1000, 3, Some(1)));
+        assert_eq!((dead.approved, dead.edited), (Some(1060), Some(1500)));
+        assert_eq!(dead.submission.as_deref(), Some("cookie"));
+        assert!(dead.author.is_none() && dead.tripcode.is_none() && dead.website.is_none() && dead.source.is_none());
+        assert!(dead.text.is_empty());
+
+        // Approving the unpublished comment again brings it back without marking it edited.
+        let restored = merge_edit(&dead, publish(&requeued("a", &published()), 3));
+        assert!(!restored.deleted);
+        assert_eq!(restored.text, "<p>original</p>");
+        assert_eq!((restored.approved, restored.edited), (Some(1060), Some(1500)));
+        assert_eq!(restored.reply_to, Some(1));
+    }
+
+    fn texts(store: &dyn CommentStore, article: &str) -> Vec<String> {
+        store.approved(article).into_iter().map(|c| c.text).collect()
+    }
+
+    /// The same checks for every store, they must behave alike.
+    fn submit_moderate_and_approve(store: &dyn CommentStore) {
+        let first = store.submit(comment("mindstorms", "first", None));
+        let spam = store.submit(comment("mindstorms", "spam", None));
+        let other = store.submit(comment("other", "elsewhere", None));
+        assert!(first != spam && spam != other);
+        let queued: Vec<String> = store.unchecked().into_iter().map(|c| c.id).collect();
+        assert_eq!(queued, vec![first.clone(), spam.clone(), other.clone()]);
+
+        let moderation = store.moderate(&[(first.clone(), Decision::Approve), (spam.clone(), Decision::Delete), ("404".to_string(), Decision::Approve)], publish);
+        assert_eq!(moderation.articles, vec!["mindstorms"]);
+        assert_eq!(moderation.missing, vec!["404"]);
+        assert_eq!(store.unchecked().into_iter().map(|c| c.id).collect::<Vec<_>>(), vec![other.clone()]);
+        // Moderating again in another tab finds nothing.
+        assert_eq!(store.moderate(&[(first.clone(), Decision::Approve)], publish).missing, vec![first.clone()]);
+
+        let second = store.submit(comment("mindstorms", "second", None));
+        store.moderate(&[(other, Decision::Approve), (second, Decision::Approve)], publish);
+        let approved = store.approved("mindstorms");
+        assert_eq!(approved.iter().map(|c| c.post_index).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(approved[0].text, "<p>first</p>");
+        assert_eq!(approved[0].submission.as_deref(), Some(first.as_str()));
+        assert_eq!(texts(store, "other"), vec!["<p>elsewhere</p>"]);
+        assert!(store.approved("missing").is_empty());
+
+        // Queued comments can be replaced and withdrawn until they are moderated.
+        let draft = store.submit(comment("mindstorms", "draft", None));
+        store.resubmit(UncheckedComment { id: draft.clone(), ..comment("mindstorms", "redrafted", None) });
+        assert_eq!(store.unchecked().into_iter().map(|c| c.text).collect::<Vec<_>>(), vec!["redrafted"]);
+        assert!(store.withdraw(&draft));
+        assert!(!store.withdraw(&draft));
+        assert!(store.unchecked().is_empty());
+    }
+
+    /// Commenters' edits, approved in place of the comment they edit.
+    fn approve_edits(store: &dyn CommentStore) {
+        let first = store.submit(comment("mindstorms", "first", None));
+        store.moderate(&[(first.clone(), Decision::Approve)], publish);
+        let edit = store.submit(comment("mindstorms", "first, edited", Some(0)));
+        assert_eq!(store.unchecked()[0].edit_of, Some(0));
+        assert_eq!(store.moderate(&[(edit, Decision::Approve)], publish).articles, Vec::<String>::new());
+        let approved = store.approved("mindstorms");
+        assert_eq!(approved.len(), 1);
+        assert_eq!(approved[0].text, "<p>first, edited</p>");
+        assert_eq!(approved[0].submission.as_deref(), Some(first.as_str()));
+        assert!(approved[0].edited.is_some());
+        // An edit of a comment that isn't there stays unpublished.
+        let orphan = store.submit(comment("mindstorms", "edit of nothing", Some(7)));
+        assert_eq!(store.moderate(&[(orphan.clone(), Decision::Approve)], publish).missing, vec![orphan]);
+        assert_eq!(store.approved("mindstorms").len(), 1);
+    }
+
+    /// Moderators changing, deleting and unpublishing published comments.
+    fn manage_published(store: &dyn CommentStore) {
+        let first = store.submit(comment("mindstorms", "first", None));
+        let second = store.submit(comment("mindstorms", "second", None));
+        store.moderate(&[(first, Decision::Approve), (second, Decision::Approve)], publish);
+
+        // Deleting leaves a tombstone that can't be deleted or replaced again.
+        let mut changed = store.approved("mindstorms")[1].clone();
+        changed.text = "<p>second, by the moderator</p>".to_string();
+        assert!(store.replace_approved("mindstorms", &changed));
+        assert_eq!(texts(store, "mindstorms"), vec!["<p>first</p>", "<p>second, by the moderator</p>"]);
+        store.submit(comment("mindstorms", "pending edit", Some(1)));
+        assert!(store.delete_approved("mindstorms", 1));
+        let tombstone = &store.approved("mindstorms")[1];
+        assert!(tombstone.deleted && tombstone.text.is_empty() && tombstone.author.is_none());
+        assert_eq!(tombstone.post_index, 1);
+        assert!(store.unchecked().is_empty());
+        assert!(!store.delete_approved("mindstorms", 1));
+        assert!(!store.replace_approved("mindstorms", &changed));
+        assert!(store.approved("mindstorms")[1].deleted);
+
+        // Unpublishing queues the comment again, approving it puts it back.
+        assert!(store.unpublish("mindstorms", 0));
+        assert!(store.approved("mindstorms")[0].deleted);
+        assert!(!store.unpublish("mindstorms", 0));
+        let requeued = store.unchecked();
+        assert_eq!(requeued.len(), 1);
+        assert_eq!((requeued[0].text.as_str(), requeued[0].edit_of), ("first", Some(0)));
+        store.moderate(&[(requeued[0].id.clone(), Decision::Approve)], publish);
+        let restored = &store.approved("mindstorms")[0];
+        assert!(!restored.deleted);
+        assert_eq!(restored.text, "<p>first</p>");
+        assert_eq!(restored.edited, None);
+    }
+
+    /// Runs `check` on a `TomlStore` in an empty temporary directory.
+    fn with_toml_store(check: fn(&dyn CommentStore)) {
+        let dir = std::env::temp_dir().join(format!("comment-store-test-{}", new_comment_id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        check(&TomlStore::open(&dir.to_string_lossy()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn toml_store() {
+        with_toml_store(submit_moderate_and_approve);
+    }
+
+    #[test]
+    fn sqlite_store() {
+        submit_moderate_and_approve(&SqliteStore::open(":memory:"));
+    }
+
+    #[test]
+    fn toml_store_edits() {
+        with_toml_store(approve_edits);
+    }
+
+    #[test]
+    fn sqlite_store_edits() {
+        approve_edits(&SqliteStore::open(":memory:"));
+    }
+
+    #[test]
+    fn toml_store_published() {
+        with_toml_store(manage_published);
+    }
+
+    #[test]
+    fn sqlite_store_published() {
+        manage_published(&SqliteStore::open(":memory:"));
+    }
+
+    #[test]
+    fn sqlite_import_keeps_ids_and_order() {
+        let dir = std::env::temp_dir().join(format!("comment-import-test-{}", new_comment_id()));
+        std::fs::create_dir_all(dir.join("comments")).unwrap();
+        let queue = UncheckedComments { comments: vec![
+            UncheckedComment { id: "00000000deadbeef".to_string(), ..comment("mindstorms", "queued first", None) },
+            UncheckedComment { id: "0123456789abcdef".to_string(), ..comment("other", "queued second", None) },
+            comment("mindstorms", "queued before there were IDs", None),
+        ]};
+        std::fs::write(dir.join("unverified_comments.toml"), toml::to_string(&queue).unwrap()).unwrap();
+        let approved = ApprovedComments { comments: vec![publish(&comment("mindstorms", "one", None), 1), publish(&comment("mindstorms", "zero", None), 0)] };
+        std::fs::write(dir.join("comments").join("mindstorms.toml"), toml::to_string(&approved).unwrap()).unwrap();
+        std::fs::write(dir.join("comment_counts.toml"), "mindstorms = 1\n").unwrap();
+
+        let store = SqliteStore::open(":memory:");
+        assert_eq!(store.import_toml(&dir.to_string_lossy()).unwrap(), (3, 2));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let queued = store.unchecked();
+        assert_eq!(queued.iter().map(|c| c.text.as_str()).collect::<Vec<_>>(), vec!["queued first", "queued second", "queued before there were IDs"]);
+        assert_eq!((queued[0].id.as_str(), queued[1].id.as_str()), ("00000000deadbeef", "0123456789abcdef"));
+        assert!(!queued[2].id.is_empty());
+        assert_eq!(texts(&store, "mindstorms"), vec!["<p>zero</p>", "<p>one</p>"]);
+
+        // The IDs in commenters' cookies still find their queued comments.
+        store.resubmit(UncheckedComment { id: "00000000deadbeef".to_string(), ..comment("mindstorms", "redrafted", None) });
+        assert_eq!(store.unchecked().into_iter().map(|c| c.text).collect::<Vec<_>>(), vec!["redrafted", "queued second", "queued before there were IDs"]);
+        assert!(store.withdraw("0123456789abcdef"));
+        let new = store.submit(comment("mindstorms", "after the import", None));
+        store.moderate(&[("00000000deadbeef".to_string(), Decision::Approve), (new, Decision::Approve)], publish);
+        assert_eq!(store.approved("mindstorms").iter().map(|c| c.post_index).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+        assert_eq!(store.unchecked().into_iter().map(|c| c.text).collect::<Vec<_>>(), vec!["queued before there were IDs"]);
+    }
+}
@@ -0,0 +1,323 @@
+use askama::Template;
+use std::io::{Seek, Write};
+use super::{Article, Meta};
+
+struct Chapter<'a> {
+    id: String,
+    file: String,
+    article: &'a Article,
+    date: String,
+    content: String,
+}
+
+struct Image {
+    id: String,
+    file: String,
+    source: std::path::PathBuf,
+    media_type: &'static str,
+}
+
+#[derive(Template)]
+#[template(path = "epub/content.opf", escape = "html")]
+struct PackageTemplate<'a> {
+    identifier: &'a str,
+    title: &'a str,
+    lang: &'a str,
+    modified: String,
+    creators: Vec<&'a str>,
+    chapters: &'a Vec<Chapter<'a>>,
+    images: &'a Vec<Image>,
+}
+
+#[derive(Template)]
+#[template(path = "epub/nav.xhtml", escape = "html")]
+struct NavTemplate<'a> {
+    title: &'a str,
+    lang: &'a str,
+    chapters: &'a Vec<Chapter<'a>>,
+}
+
+#[derive(Template)]
+#[template(path = "epub/chapter.xhtml", escape = "html")]
+struct ChapterTemplate<'a> {
+    lang: &'a str,
+    chapter: &'a Chapter<'a>,
+}
+
+const CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+    <rootfiles>
+        <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+    </rootfiles>
+</container>
+"#;
+
+fn media_type(path: &str) -> Option<&'static str> {
+    match path.rsplit('.').next()?.to_lowercase().as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "svg" => Some("image/svg+xml"),
+        _ => None,
+    }
+}
+
+/// Points `src="/..."` references to files in `static/` at copies inside the book.
+fn embed_images(html: &str, images: &mut Vec<Image>) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(i) = rest.find("src=") {
+        out.push_str(&rest[..i + 4]);
+        rest = &rest[i + 4..];
+        // The markdown renderer quotes attributes with single quotes.
+        let quote = match rest.chars().next() {
+            Some(q) if (q == '"' || q == '\'') && rest[1..].starts_with('/') => q,
+            _ => continue,
+        };
+        let end = rest[1..].find(quote).map_or(rest.len(), |e| e + 1);
+        let path = &rest[2..end];
+        let source: std::path::PathBuf = ["static", path].iter().collect();
+        match media_type(path) {
+            Some(mt) if source.is_file() && !path.contains("..") => {
+                let file = format!("images/{}", path);
+                if !images.iter().any(|im| im.file == file) {
+                    images.push(Image { id: format!("image{}", images.len()), file: file.clone(), source, media_type: mt });
+                }
+                out.push(quote);
+                out.push_str(&file);
+                rest = &rest[end..];
+            },
+            _ => (),
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// XML only predefines five named entities, the other HTML ones from article sources become
+/// numeric character references. An `&` that doesn't start a known entity is escaped.
+fn numeric_entities(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let name_len = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+        let (name, after) = rest.split_at(name_len);
+        if rest.starts_with('#') || (after.starts_with(';') && ["amp", "lt", "gt", "quot", "apos"].contains(&name)) {
+            out.push('&');
+            continue;
+        }
+        match markup5ever::data::NAMED_ENTITIES.get(format!("{};", name).as_str()) {
+            Some(&(first, second)) if !name.is_empty() && after.starts_with(';') => {
+                out.push_str(&format!("&#{};", first));
+                if second != 0 {
+                    out.push_str(&format!("&#{};", second));
+                }
+                rest = &after[1..];
+            },
+            _ => out.push_str("&amp;"),
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// EPUB content documents are XHTML, so void elements from the markdown renderer need to be closed
+/// and named entities replaced.
+fn to_xhtml(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(i) = rest.find('<') {
+        out.push_str(&rest[..i]);
+        let end = rest[i..].find('>').map_or(rest.len(), |e| i + e);
+        let tag = &rest[i..end];
+        out.push_str(tag);
+        let name: String = tag[1..].chars().take_while(|c| c.is_ascii_alphanumeric()).collect();
+        let is_void = ["br", "hr", "img", "input", "meta", "link", "wbr", "col", "area", "source"].contains(&name.to_lowercase().as_str());
+        if is_void && !tag.ends_with('/') {
+            out.push('/');
+        }
+        rest = &rest[end..];
+        if rest.starts_with('>') {
+            out.push('>');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    numeric_entities(&out)
+}
+
+pub fn write_epub<W: Write + Seek>(out: W, identifier: &str, title: &str, lang: &str, articles: &[&Article], meta: &Meta) -> zip::result::ZipResult<W> {
+    let mut images: Vec<Image> = Vec::new();
+    let chapters: Vec<Chapter> = articles.iter().enumerate().map(|(i, a)| Chapter {
+        id: format!("chapter{}", i),
+        file: format!("{}.xhtml", a.name),
+        article: a,
+        date: a.date.to_string(),
+        content: to_xhtml(&embed_images(&meta.articles_map[&a.name].1, &mut images)),
+    }).collect();
+    let mut creators: Vec<&str> = Vec::new();
+    for a in articles {
+        for slug in &a.author {
+            let name = meta.authors[slug].name.as_str();
+            if !creators.contains(&name) {
+                creators.push(name);
+            }
+        }
+    }
+
+    let mut zip = zip::ZipWriter::new(out);
+    let stored = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let deflated = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    // The mimetype has to be the first entry and uncompressed.
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(b"application/epub+zip")?;
+    zip.start_file("META-INF/container.xml", deflated)?;
+    zip.write_all(CONTAINER_XML.as_bytes())?;
+
+    let package = PackageTemplate {
+        identifier,
+        title,
+        lang,
+        modified: chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        creators,
+        chapters: &chapters,
+        images: &images,
+    };
+    zip.start_file("OEBPS/content.opf", deflated)?;
+    zip.write_all(package.render().unwrap().as_bytes())?;
+
+    let nav = NavTemplate { title, lang, chapters: &chapters };
+    zip.start_file("OEBPS/nav.xhtml", deflated)?;
+    zip.write_all(nav.render().unwrap().as_bytes())?;
+
+    for chapter in &chapters {
+        zip.start_file(format!("OEBPS/{}", chapter.file), deflated)?;
+        zip.write_all(ChapterTemplate { lang, chapter }.render().unwrap().as_bytes())?;
+    }
+    for image in &images {
+        zip.start_file(format!("OEBPS/{}", image.file), deflated)?;
+        zip.write_all(&std::fs::read(&image.source)?)?;
+    }
+    zip.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that `xml` is well-formed: one root element, balanced and properly nested tags,
+    /// quoted attributes and no entity references besides the predefined and numeric ones.
+    fn check_well_formed(xml: &str) -> Result<(), String> {
+        fn check_text(text: &str) -> Result<(), String> {
+            let mut rest = text;
+            while let Some(i) = rest.find('&') {
+                rest = &rest[i + 1..];
+                let end = rest.find(';').ok_or_else(|| format!("unterminated reference in {:?}", text))?;
+                let name = &rest[..end];
+                let numeric = name.strip_prefix("#x").map(|n| u32::from_str_radix(n, 16).is_ok())
+                    .or_else(|| name.strip_prefix('#').map(|n| n.parse::<u32>().is_ok()))
+                    .unwrap_or(false);
+                if !numeric && !["amp", "lt", "gt", "quot", "apos"].contains(&name) {
+                    return Err(format!("undefined entity &{};", name));
+                }
+                rest = &rest[end + 1..];
+            }
+            if text.contains('<') {
+                return Err(format!("stray < in {:?}", text));
+            }
+            Ok(())
+        }
+
+        let mut stack: Vec<&str> = Vec::new();
+        let mut roots = 0;
+        let mut rest = xml;
+        while let Some(i) = rest.find('<') {
+            let text = &rest[..i];
+            check_text(text)?;
+            if stack.is_empty() && !text.trim().is_empty() {
+                return Err(format!("text outside the root: {:?}", text));
+            }
+            rest = &rest[i..];
+            for (open, close) in [("<?", "?>"), ("<!--", "-->"), ("<!DOCTYPE", ">")].iter() {
+                if rest.starts_with(open) {
+                    let end = rest.find(close).ok_or("unterminated markup declaration")?;
+                    rest = &rest[end + close.len()..];
+                }
+            }
+            if !rest.starts_with('<') {
+                continue;
+            }
+            let end = rest.find('>').ok_or("unterminated tag")?;
+            let tag = &rest[1..end];
+            rest = &rest[end + 1..];
+            if let Some(name) = tag.strip_prefix('/') {
+                match stack.pop() {
+                    Some(open) if open == name.trim() => {},
+                    open => return Err(format!("</{}> closes {:?}", name, open)),
+                }
+                continue;
+            }
+            let self_closing = tag.ends_with('/');
+            let tag = tag.trim_end_matches('/');
+            let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+            let (name, mut attributes) = tag.split_at(name_end);
+            loop {
+                attributes = attributes.trim_start();
+                if attributes.is_empty() {
+                    break;
+                }
+                let eq = attributes.find('=').ok_or_else(|| format!("attribute without value in <{}>", tag))?;
+                let quote = attributes[eq + 1..].chars().next().filter(|&c| c == '"' || c == '\'')
+                    .ok_or_else(|| format!("unquoted attribute in <{}>", tag))?;
+                let value_start = eq + 2;
+                let value_end = attributes[value_start..].find(quote).ok_or("unterminated attribute")? + value_start;
+                check_text(&attributes[value_start..value_end])?;
+                attributes = &attributes[value_end + 1..];
+            }
+            if stack.is_empty() {
+                roots += 1;
+            }
+            if !self_closing {
+                stack.push(name);
+            }
+        }
+        check_text(rest)?;
+        match (roots, stack.last()) {
+            (1, None) if rest.trim().is_empty() => Ok(()),
+            (_, Some(open)) => Err(format!("<{}> is never closed", open)),
+            _ => Err(format!("{} root elements", roots)),
+        }
+    }
+
+    #[test]
+    fn chapter_is_well_formed_xml() {
+        let article: Article = toml::from_str(r#"
+            name = "entities"
+            description = "Entities & void elements"
+            title = "Fish & <chips>"
+            date = 2020-01-01
+            tags = ["Web"]
+        "#).unwrap();
+        let source = "Fish & chips \"quoted\"\n\n---\n\nLine  \nbreak ![An image](/images/none.png)\n\n    <br> in code\n";
+        let html = format!("{}<p>Wait&hellip; a&nbsp;moment &copy; R&D &amp; &#8212;<br></p>", markdown::to_html(source));
+        let chapter = Chapter {
+            id: "chapter0".to_string(),
+            file: "entities.xhtml".to_string(),
+            article: &article,
+            date: article.date.to_string(),
+            content: to_xhtml(&html),
+        };
+        let xml = ChapterTemplate { lang: "en", chapter: &chapter }.render().unwrap();
+        check_well_formed(&xml).unwrap_or_else(|e| panic!("{}\n{}", e, xml));
+        assert!(xml.contains("Wait&#8230; a&#160;moment &#169; R&amp;D &amp; &#8212;"), "{}", xml);
+    }
+
+    #[test]
+    fn checker_rejects_html_entities() {
+        assert!(check_well_formed("<p>a&nbsp;b</p>").is_err());
+        assert!(check_well_formed("<p><br></p>").is_err());
+        assert!(check_well_formed("<p>a&#160;<br/></p>").is_ok());
+    }
+}
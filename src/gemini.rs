@@ -0,0 +1,297 @@
+use rustls::Session;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::io::{BufReader, Read, Write};
+use std::sync::Arc;
+use super::{Messages, Meta, SharedMeta, format_article_date, resolve_tags, tagged_articles};
+
+#[derive(Clone, Deserialize)]
+pub struct GeminiConfig {
+    bind: String,
+    cert: String,
+    key: String,
+    /// How many connections are handled at once, further ones wait to be accepted.
+    #[serde(default = "default_workers")]
+    workers: usize,
+}
+
+fn default_workers() -> usize {
+    8
+}
+
+/// Gemini requests have no language preference, pages are in the article's language or the configured locale.
+pub struct Localization {
+    pub messages: HashMap<String, Messages>,
+    pub locale: String,
+    pub timezone: chrono_tz::Tz,
+}
+
+struct Capsule {
+    meta: Arc<Meta>,
+    articles: HashMap<String, String>,
+    localization: Arc<Localization>,
+}
+
+/// Splits `[text](url)` and `![alt](url)` out of a line, returning the plain text and the links.
+pub fn extract_links(line: &str) -> (String, Vec<(String, String)>) {
+    let mut text = String::with_capacity(line.len());
+    let mut links = Vec::new();
+    let mut rest = line;
+    while let Some(start) = rest.find('[') {
+        let close = match rest[start..].find("](") {
+            Some(c) => start + c,
+            None => break,
+        };
+        let end = match rest[close..].find(')') {
+            Some(e) => close + e,
+            None => break,
+        };
+        let label = &rest[start + 1..close];
+        let url = &rest[close + 2..end];
+        text.push_str(rest[..start].trim_end_matches('!'));
+        text.push_str(label);
+        links.push((url.to_string(), label.to_string()));
+        rest = &rest[end + 1..];
+    }
+    text.push_str(rest);
+    (text, links)
+}
+
+/// Converts markdown to gemtext: paragraphs become single lines and inline links are listed after them.
+pub fn markdown_to_gemtext(md: &str) -> String {
+    let mut out = String::with_capacity(md.len());
+    let mut paragraph: Vec<String> = Vec::new();
+    let mut links: Vec<(String, String)> = Vec::new();
+    let mut preformatted = false;
+
+    fn flush(out: &mut String, paragraph: &mut Vec<String>, links: &mut Vec<(String, String)>) {
+        if !paragraph.is_empty() {
+            out.push_str(&paragraph.join(" "));
+            out.push('\n');
+            paragraph.clear();
+        }
+        for (url, label) in links.drain(..) {
+            out.push_str(&format!("=> {} {}\n", url, label));
+        }
+    }
+
+    for line in md.lines() {
+        if line.trim_start().starts_with("```") {
+            flush(&mut out, &mut paragraph, &mut links);
+            preformatted = !preformatted;
+            out.push_str("```\n");
+            continue;
+        }
+        if preformatted {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            flush(&mut out, &mut paragraph, &mut links);
+            out.push('\n');
+        } else if trimmed.starts_with('#') {
+            flush(&mut out, &mut paragraph, &mut links);
+            let level = trimmed.chars().take_while(|&c| c == '#').count();
+            let (text, mut l) = extract_links(trimmed[level..].trim());
+            out.push_str(&format!("{} {}\n", "#".repeat(level.min(3)), text));
+            links.append(&mut l);
+        } else if trimmed.starts_with("* ") || trimmed.starts_with("- ") || trimmed.starts_with("+ ") {
+            flush(&mut out, &mut paragraph, &mut links);
+            let (text, mut l) = extract_links(&trimmed[2..]);
+            out.push_str(&format!("* {}\n", text));
+            links.append(&mut l);
+        } else if let Some(quote) = trimmed.strip_prefix('>') {
+            flush(&mut out, &mut paragraph, &mut links);
+            let (text, mut l) = extract_links(quote.trim());
+            out.push_str(&format!("> {}\n", text));
+            links.append(&mut l);
+        } else {
+            let (text, mut l) = extract_links(trimmed);
+            paragraph.push(text);
+            links.append(&mut l);
+        }
+    }
+    flush(&mut out, &mut paragraph, &mut links);
+    out
+}
+
+impl Capsule {
+    fn new(meta: Arc<Meta>, localization: Arc<Localization>) -> Capsule {
+        let articles = meta.sources.iter().map(|(name, md)| (name.clone(), markdown_to_gemtext(md))).collect();
+        Capsule { meta, articles, localization }
+    }
+
+    fn index(&self) -> String {
+        let mut page = String::from("# Lesser Scholar\n\n");
+        for a in &self.meta.recent_articles {
+            page.push_str(&format!("=> /a/{} {} {}\n", a.name, a.date, a.title));
+        }
+        page.push_str("\n## Tags\n\n");
+        for t in &self.meta.tags {
+            page.push_str(&format!("=> /tag/{} {} ({})\n", t.name, t.name, t.count));
+        }
+        page
+    }
+
+    fn respond(&self, request: &str) -> String {
+        let path = match request.strip_prefix("gemini://") {
+            None => return "59 Bad request\r\n".to_string(),
+            Some(rest) => rest.find('/').map_or("/", |i| &rest[i..]),
+        };
+        let path = path.split('?').next().unwrap_or_default();
+        let path = percent_encoding::percent_decode_str(path).decode_utf8_lossy();
+        if path == "/" || path.is_empty() {
+            format!("20 text/gemini\r\n{}", self.index())
+        } else if let Some(name) = path.strip_prefix("/a/") {
+            match self.meta.articles_map.get(name) {
+                Some((a, _)) if a.is_public() => {
+                    let mut page = format!("# {}\n\n{}\n", a.title, self.articles[name]);
+                    let l = &self.localization;
+                    let msg = l.messages.get(&a.lang).unwrap_or(&l.messages[&l.locale]);
+                    page.push_str(&format!("{} {}.\n", msg.posted_on, format_article_date(&a.date, &l.timezone, &msg.date_format)));
+                    for t in &a.tags {
+                        page.push_str(&format!("=> /tag/{} {}\n", t, t));
+                    }
+                    format!("20 text/gemini\r\n{}", page)
+                },
+                _ => "51 Not found\r\n".to_string(),
+            }
+        } else if let Some(name) = path.strip_prefix("/tag/") {
+            match resolve_tags(&self.meta, name) {
+                None => "51 Not found\r\n".to_string(),
+                Some((tags, true)) => {
+                    let canonical: Vec<&str> = tags.iter().map(|t| t.name.as_str()).collect();
+                    format!("31 /tag/{}\r\n", canonical.join("+"))
+                },
+                Some((tags, false)) => {
+                    let names: Vec<&str> = tags.iter().map(|t| t.name.as_str()).collect();
+                    let mut page = format!("# Posts in {}\n\n", names.join(" + "));
+                    for a in tagged_articles(&self.meta, &tags) {
+                        page.push_str(&format!("=> /a/{} {} {}\n", a.name, a.date, a.title));
+                    }
+                    page.push_str("\n=> / Lesser Scholar\n");
+                    format!("20 text/gemini\r\n{}", page)
+                },
+            }
+        } else {
+            "51 Not found\r\n".to_string()
+        }
+    }
+}
+
+fn tls_config(config: &GeminiConfig) -> rustls::ServerConfig {
+    let open = |path: &str| BufReader::new(std::fs::File::open(path).unwrap_or_else(|_| panic!("Failed to open {}", path)));
+    let certs = rustls::internal::pemfile::certs(&mut open(&config.cert)).expect("Failed to parse gemini certificate");
+    let mut keys = rustls::internal::pemfile::pkcs8_private_keys(&mut open(&config.key)).expect("Failed to parse gemini key");
+    if keys.is_empty() {
+        keys = rustls::internal::pemfile::rsa_private_keys(&mut open(&config.key)).expect("Failed to parse gemini key");
+    }
+    assert!(!keys.is_empty(), "No private key in {}", config.key);
+    let mut tls = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    tls.set_single_cert(certs, keys.remove(0)).expect("Bad gemini certificate or key");
+    tls
+}
+
+/// How long a client has for the TLS handshake and the request line together.
+const REQUEST_TIME: std::time::Duration = std::time::Duration::from_secs(10);
+/// How long a client has to receive the response.
+const RESPONSE_TIME: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// A connection with an overall deadline instead of a timeout per read, so a client sending a
+/// byte at a time can't keep a worker busy.
+struct Deadline<'a> {
+    tcp: &'a mut std::net::TcpStream,
+    deadline: std::time::Instant,
+}
+
+impl Deadline<'_> {
+    fn remaining(&self) -> std::io::Result<std::time::Duration> {
+        match self.deadline.checked_duration_since(std::time::Instant::now()) {
+            Some(remaining) if remaining > std::time::Duration::from_millis(0) => Ok(remaining),
+            _ => Err(std::io::ErrorKind::TimedOut.into()),
+        }
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.tcp.set_read_timeout(Some(self.remaining()?))?;
+        self.tcp.read(buf)
+    }
+}
+
+impl Write for Deadline<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.tcp.set_write_timeout(Some(self.remaining()?))?;
+        self.tcp.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.tcp.flush()
+    }
+}
+
+fn handle(mut tcp: std::net::TcpStream, tls: Arc<rustls::ServerConfig>, capsule: &Capsule) -> std::io::Result<()> {
+    let mut io = Deadline { tcp: &mut tcp, deadline: std::time::Instant::now() + REQUEST_TIME };
+    let mut session = rustls::ServerSession::new(&tls);
+    // Requests are a single URL of at most 1024 bytes followed by CRLF.
+    let mut request: Vec<u8> = Vec::new();
+    let mut byte = [0u8; 1];
+    while !request.ends_with(b"\r\n") {
+        if request.len() > 1026 || rustls::Stream::new(&mut session, &mut io).read(&mut byte)? == 0 {
+            return Ok(());
+        }
+        request.push(byte[0]);
+    }
+    let response = match std::str::from_utf8(&request) {
+        Ok(url) => capsule.respond(url.trim_end()),
+        Err(_) => "59 Bad request\r\n".to_string(),
+    };
+    io.deadline = std::time::Instant::now() + RESPONSE_TIME;
+    rustls::Stream::new(&mut session, &mut io).write_all(response.as_bytes())?;
+    session.send_close_notify();
+    while session.wants_write() {
+        session.write_tls(&mut io)?;
+    }
+    Ok(())
+}
+
+/// Starts the Gemini listener on its own thread, handing connections to a fixed pool of workers.
+/// Each worker converts the articles again when the shared content has been reloaded.
+pub fn serve(config: &GeminiConfig, meta: SharedMeta, localization: Localization) {
+    let localization = Arc::new(localization);
+    let tls = Arc::new(tls_config(config));
+    let listener = std::net::TcpListener::bind(&config.bind).expect("Failed to bind gemini listener");
+    // The queue holds no connections of its own, so the listener stops accepting while all workers are busy.
+    let (sender, receiver) = std::sync::mpsc::sync_channel::<std::net::TcpStream>(0);
+    let receiver = Arc::new(std::sync::Mutex::new(receiver));
+    for _ in 0..config.workers.max(1) {
+        let tls = tls.clone();
+        let meta = meta.clone();
+        let receiver = receiver.clone();
+        let localization = localization.clone();
+        std::thread::spawn(move || {
+            let mut capsule = Capsule::new(meta.read().unwrap().clone(), localization.clone());
+            loop {
+                let stream = match receiver.lock().unwrap().recv() {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let current = meta.read().unwrap().clone();
+                if !Arc::ptr_eq(&capsule.meta, &current) {
+                    capsule = Capsule::new(current, localization.clone());
+                }
+                let _ = handle(stream, tls.clone(), &capsule);
+            }
+        });
+    }
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if sender.send(stream).is_err() {
+                return;
+            }
+        }
+    });
+}
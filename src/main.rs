@@ -1,32 +1,27 @@
-use actix_web::{web, Responder};
+use actix_web::{web, Responder, HttpMessage};
 use actix_service::Service;
 use askama::Template;
 use serde_derive::{Serialize, Deserialize};
-use std::cell::{RefCell, Cell};
-use std::num::Wrapping;
+use std::cell::RefCell;
+use rand::Rng;
 use std::collections::HashMap;
 use std::iter::FromIterator;
 use std::io::Write;
 use std::fs::OpenOptions;
 use std::sync::{Arc, RwLock, Mutex};
 
-// Random
-
-#[derive(Default, Clone, Copy)]
-struct RandomGenerator {
-    state: Wrapping<u32>,
-}
-
-fn init_rng() -> RandomGenerator {
-    RandomGenerator {state: Wrapping(0)}
-}
-
-fn get_random(generator: &Cell<RandomGenerator>) -> u32 {
-    let s = generator.get().state * Wrapping(2147001325) + Wrapping(715136305);
-    generator.replace(RandomGenerator{state: s});
-    return 0x31415926 ^ ((s.0 >> 16) + (s.0 << 16));
-}
-
+mod activitypub;
+mod api;
+mod comment_admin;
+mod comment_edit;
+mod comment_store;
+mod epub;
+mod gemini;
+mod micropub;
+mod newsletter;
+mod plaintext;
+mod tripcode;
+mod webmention;
 
 // Logging 
 
@@ -67,26 +62,63 @@ fn sum_compacted_log(a: CompactedLog, mut b: CompactedLog) -> CompactedLog {
     b
 }
 
+/// The site's content, shared by the web and gemini servers and replaced as a whole when it is read again.
+type SharedMeta = Arc<RwLock<Arc<Meta>>>;
+
 // Comments 
 
 struct AppState {
-    rng: Cell<RandomGenerator>,
     quote_data: Quotes,
-    meta: Meta,
+    meta: SharedMeta,
     log_file_draft_lock: Arc<Mutex<()>>,
-    log_output: RefCell<(std::fs::File, chrono::Date<chrono::Utc>)>,
-    unchecked_comments_file_lock: Arc<Mutex<()>>,
+    log_output: RefCell<(std::fs::File, chrono::Date<chrono_tz::Tz>)>,
+    comments: Arc<dyn comment_store::CommentStore>,
+    mentions_file_lock: Arc<Mutex<()>>,
+    content_file_lock: Arc<Mutex<()>>,
+    micropub_token: Option<String>,
+    activitypub: Option<Arc<activitypub::Actor>>,
+    followers_file_lock: Arc<Mutex<()>>,
+    subscribers_file_lock: Arc<Mutex<()>>,
     // TODO: this is used in layout. Layout should not read data files.
     recent_comments: Arc<RwLock<TomlFile<RecentComments>>>,
     admin_password: String,
+    cookie_key: actix_web::cookie::Key,
+    tripcode_key: ring::hmac::Key,
+    config: Config,
+    timezone: chrono_tz::Tz,
+    messages: HashMap<String, Messages>,
+}
+
+impl AppState {
+    /// The current content. Handlers take it once so that a reload doesn't change it halfway through a request.
+    fn meta(&self) -> Arc<Meta> {
+        self.meta.read().unwrap().clone()
+    }
+
+    /// Reads `src/meta.toml` and the articles again for both servers, keeping the previous content when it has errors.
+    fn reload_meta(&self) {
+        match read_meta_file().and_then(make_meta) {
+            Ok(meta) => {
+                let meta = Arc::new(meta);
+                *self.meta.write().unwrap() = meta.clone();
+                if let Some(actor) = &self.activitypub {
+                    actix_web::rt::spawn(activitypub::deliver_new_articles(actor.clone(), meta, self.followers_file_lock.clone()));
+                }
+            },
+            Err(e) => eprintln!("Content error, keeping the previous content: {}", e),
+        }
+    }
 }
 
 struct LayoutTemplate<'a> {
-    quote_text: &'a str,
-    quote_author: &'a str,
-    tags: &'a Vec<Tag>,
+    meta: Arc<Meta>,
+    locale: &'a str,
+    msg: &'a Messages,
+    timezone: chrono_tz::Tz,
+    quote: &'a Quote,
     recent_comments: Vec<Article>,
     recent_articles: Vec<Article>,
+    newsletter: bool,
 }
 
 #[derive(Template)]
@@ -103,6 +135,109 @@ struct ArticleTemplate<'a> {
     article: &'a Article,
     content: &'a str,
     comments: Vec<DisplayComment>,
+    /// Queue IDs of the reader's own comments on this article that wait for moderation.
+    pending: Vec<String>,
+    mentions: Vec<webmention::Mention>,
+    translations: Vec<Article>,
+    json_ld: String,
+}
+
+impl ArticleTemplate<'_> {
+    /// The description for the `p-summary` attribute, the template itself doesn't escape.
+    fn summary(&self) -> String {
+        html_escape(&self.article.description)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "article_print.html", escape = "none")]
+struct ArticlePrintTemplate<'a> {
+    layout: LayoutTemplate<'a>,
+    article: &'a Article,
+    content: &'a str,
+    comments: Vec<DisplayComment>,
+}
+
+#[derive(Template)]
+#[template(path = "comment.html", escape = "none")]
+struct CommentTemplate<'a> {
+    layout: LayoutTemplate<'a>,
+    article: &'a Article,
+    comment: DisplayComment,
+}
+
+/// The no-JavaScript comment preview, with the form filled in again. The form values are escaped.
+#[derive(Template)]
+#[template(path = "comment_preview.html", escape = "none")]
+struct CommentPreviewTemplate<'a> {
+    layout: LayoutTemplate<'a>,
+    article: &'a Article,
+    comment: DisplayComment,
+    author: String,
+    website: String,
+    text: String,
+}
+
+#[derive(Template)]
+#[template(path = "comment-fragment.html", escape = "none")]
+struct CommentFragmentTemplate<'a> {
+    layout: LayoutTemplate<'a>,
+    article: &'a Article,
+    comment: DisplayComment,
+}
+
+#[derive(Template)]
+#[template(path = "article_unlock.html")]
+struct ArticleUnlockTemplate<'a> {
+    layout: LayoutTemplate<'a>,
+    article: &'a Article,
+    wrong_passphrase: bool,
+}
+
+#[derive(Template)]
+#[template(path = "author.html")]
+struct AuthorTemplate<'a> {
+    layout: LayoutTemplate<'a>,
+    author: &'a Author,
+    articles: &'a Vec<Article>,
+}
+
+#[derive(Template)]
+#[template(path = "feed.xml")]
+struct FeedTemplate<'a> {
+    title: &'a str,
+    base_url: &'a str,
+    path: &'a str,
+    articles: &'a Vec<Article>,
+    authors: &'a HashMap<String, Author>,
+}
+
+impl<'a> FeedTemplate<'a> {
+    fn author_names(&self, article: &'a Article) -> Vec<&'a str> {
+        article.author.iter().map(|slug| self.authors[slug].name.as_str()).collect()
+    }
+
+    fn updated(&self) -> String {
+        self.articles.first().map_or("1970-01-01T00:00:00Z".to_string(), |a| atom_date(&a.date))
+    }
+
+    fn entry_date(&self, date: &toml::value::Datetime) -> String {
+        atom_date(date)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "quotes.html")]
+struct QuotesTemplate<'a> {
+    layout: LayoutTemplate<'a>,
+    quotes: &'a Vec<Quote>,
+}
+
+#[derive(Template)]
+#[template(path = "quote.html")]
+struct QuoteTemplate<'a> {
+    layout: LayoutTemplate<'a>,
+    permalink: &'a Quote,
 }
 
 #[derive(Template)]
@@ -121,7 +256,7 @@ struct ArchiveTemplate<'a> {
 #[template(path = "tag.html")]
 struct TagTemplate<'a> {
     layout: LayoutTemplate<'a>,
-    tag: &'a Tag,
+    tags: Vec<&'a Tag>,
     articles: Vec<Article>,
 }
 
@@ -143,7 +278,14 @@ struct ErrorTemplate<'a> {
 struct CommentApprovalsTemplate<'a> {
     layout: LayoutTemplate<'a>,
     comments: Vec<(String, &'a UncheckedComment)>,
-    author_name_fn: fn (&Option<String>) -> String,
+    mentions: Vec<webmention::Mention>,
+    /// Articles with published comments and how many, linking to `comment_admin`.
+    published: Vec<(&'a Article, usize)>,
+    author_name_fn: fn (&Option<String>, &str) -> String,
+    /// The template doesn't escape, article names come from the submitted url.
+    html_escape_fn: fn (&str) -> String,
+    problems: Vec<String>,
+    csrf_token: &'a str,
 }
 
 #[derive(Template)]
@@ -153,10 +295,156 @@ struct StatsTemplate<'a> {
     stats: &'a Vec<(String, usize)>,
 }
 
+impl<'a> LayoutTemplate<'a> {
+    fn format_date(&self, date: &toml::value::Datetime) -> String {
+        format_article_date(date, &self.timezone, &self.msg.date_format)
+    }
+
+    fn author_name(&self, slug: &str) -> &str {
+        &self.meta.authors[slug].name
+    }
+}
+
+#[derive(Clone, Deserialize)]
+struct Config {
+    locale: String,
+    timezone: String,
+    base_url: String,
+    #[serde(default)]
+    quote_mode: QuoteMode,
+    gemini: Option<gemini::GeminiConfig>,
+    activitypub: Option<activitypub::ActivityPubConfig>,
+    newsletter: Option<newsletter::NewsletterConfig>,
+    #[serde(default)]
+    comment_store: comment_store::CommentStoreKind,
+    #[serde(default)]
+    comment_threads: CommentThreadsConfig,
+    /// How long commenters can edit or delete their comments after they are approved.
+    #[serde(default = "default_comment_edit_minutes")]
+    comment_edit_minutes: u64,
+}
+
+fn default_comment_edit_minutes() -> u64 {
+    30
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+struct CommentThreadsConfig {
+    /// Levels of nesting, deeper replies are shown flat under the last comment that fits.
+    max_depth: usize,
+    /// Branches with more replies than this start collapsed.
+    collapse_after: usize,
+}
+
+impl Default for CommentThreadsConfig {
+    fn default() -> Self {
+        CommentThreadsConfig { max_depth: 4, collapse_after: 5 }
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum QuoteMode {
+    /// A new quote on every page load.
+    #[default]
+    Random,
+    /// The same quote for everyone for the whole day, in the display timezone.
+    Daily,
+}
+
+#[derive(Clone, Deserialize)]
+struct Messages {
+    date_format: String,
+    home: String,
+    archive: String,
+    about: String,
+    quotes: String,
+    latest_articles: String,
+    latest_comments: String,
+    tags: String,
+    posted_on: String,
+    tagged: String,
+    by: String,
+    articles: String,
+    feed: String,
+    also_available_in: String,
+    comments: String,
+    nothing_here_yet: String,
+    comment_on: String,
+    replied_to: String,
+    replied_to_missing: String,
+    referenced_by: String,
+    view_in_article: String,
+    tripcode_heading: String,
+    tripcode_hint: String,
+    comment_edit: String,
+    comment_edit_heading: String,
+    comment_edit_note: String,
+    comment_delete_note: String,
+    comment_edited: String,
+    comment_deleted: String,
+    preview: String,
+    comment_preview: String,
+    comment_pending: String,
+    comment_save: String,
+    comment_delete: String,
+    wrote: String,
+    reply: String,
+    replies: String,
+    write_comment: String,
+    nickname: String,
+    website: String,
+    optional: String,
+    moderation_note: String,
+    submit: String,
+    anon: String,
+    posts_in: String,
+    subtags: String,
+    archive_under_construction: String,
+    page_not_found: String,
+    something_went_wrong: String,
+    comment_too_long: String,
+    comment_empty: String,
+    private_article: String,
+    private_article_note: String,
+    wrong_passphrase: String,
+    unlock: String,
+    print_version: String,
+    markdown_source: String,
+    mentions: String,
+    mention_reply: String,
+    mention_like: String,
+    mention_repost: String,
+    mention_bookmark: String,
+    mention_mention: String,
+    newsletter: String,
+    newsletter_note: String,
+    newsletter_email: String,
+    newsletter_subscribe: String,
+    newsletter_check_email: String,
+    newsletter_confirmed: String,
+    newsletter_invalid_email: String,
+    newsletter_invalid_link: String,
+    newsletter_unsubscribe_question: String,
+    newsletter_unsubscribe: String,
+    newsletter_unsubscribed: String,
+    newsletter_confirm_subject: String,
+    newsletter_confirm_body: String,
+    newsletter_confirm_ignore: String,
+    newsletter_digest_subject: String,
+    newsletter_digest_intro: String,
+    newsletter_unsubscribe_note: String,
+}
+
 #[derive(Clone, Deserialize)]
 struct Quote {
+    id: String,
     text: String,
     author: String,
+    source: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
 }
 
 #[derive(Clone, Deserialize)]
@@ -171,6 +459,14 @@ struct Project {
     order: i32,
 }
 
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Access {
+    #[default]
+    Public,
+    Private { passphrase: String },
+}
+
 #[derive(Clone, Deserialize)]
 struct Article {
     name: String,
@@ -178,11 +474,65 @@ struct Article {
     title: String,
     date: toml::value::Datetime,
     tags: Vec<String>,
+    #[serde(default)]
+    access: Access,
+    #[serde(default = "default_lang")]
+    lang: String,
+    translation: Option<String>,
+    #[serde(default)]
+    author: Vec<String>,
+    review: Option<Review>,
+    /// Drafts are left out of every listing and only shown to the admin.
+    #[serde(default)]
+    draft: bool,
+}
+
+fn default_lang() -> String {
+    "en".to_string()
+}
+
+/// What a book review article is about, for structured data.
+#[derive(Clone, Deserialize)]
+struct Review {
+    item: String,
+    item_author: Option<String>,
+    isbn: Option<String>,
+    rating: Option<u8>,
+    #[serde(default = "default_best_rating")]
+    best_rating: u8,
+}
+
+fn default_best_rating() -> u8 {
+    5
+}
+
+impl Article {
+    fn is_public(&self) -> bool {
+        self.access == Access::Public && !self.draft
+    }
+}
+
+#[derive(Clone, Deserialize)]
+struct AuthorLink {
+    title: String,
+    url: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct Author {
+    slug: String,
+    name: String,
+    bio: String,
+    avatar: Option<String>,
+    #[serde(default)]
+    links: Vec<AuthorLink>,
 }
 
 #[derive(Clone, Deserialize)]
 struct MetaFile {
-    tags: Vec<String>,
+    #[serde(default)]
+    authors: Vec<Author>,
+    tags: Vec<TagDef>,
     projects: Vec<Project>,
     articles: Vec<Article>,
 }
@@ -192,6 +542,24 @@ struct Tag {
     name: String,
     count: i32,
     articles: Vec<String>,
+    description: String,
+    aliases: Vec<String>,
+    parent: Option<String>,
+    children: Vec<String>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum TagDef {
+    Name(String),
+    Full {
+        name: String,
+        #[serde(default)]
+        description: String,
+        #[serde(default)]
+        aliases: Vec<String>,
+        parent: Option<String>,
+    },
 }
 
 #[derive(Clone, Deserialize)]
@@ -199,16 +567,31 @@ struct Meta {
     tags: Vec<Tag>,
     projects_map: HashMap<String, (Project, String)>,
     articles_map: HashMap<String, (Article, String)>,
+    /// Markdown source of each article, for the gemini and plain text renderers.
+    sources: HashMap<String, String>,
     recent_articles: Vec<Article>,
+    translations: HashMap<String, Vec<Article>>,
+    articles_by_lang: HashMap<String, Vec<Article>>,
+    authors: HashMap<String, Author>,
+    articles_by_author: HashMap<String, Vec<Article>>,
 }
 
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct UncheckedComment {
+    /// Given by the `CommentStore` when the comment is queued, the moderation form refers to it.
+    #[serde(default)]
+    pub id: String,
     pub timestamp: u64,
     pub author: Option<String>,
+    /// From `name#secret` in the author field, the secret itself is never stored.
+    #[serde(default)]
+    pub tripcode: Option<String>,
     pub website: Option<String>,
     pub text: String,
     pub article: String,
+    /// The `post_index` of the published comment this is an edit of.
+    #[serde(default)]
+    pub edit_of: Option<i64>,
 }
 
 #[derive(Default, Serialize, Deserialize)]
@@ -216,14 +599,25 @@ pub struct UncheckedComments {
     pub comments: Vec<UncheckedComment>,
 }
 
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct ApprovedComment {
     pub timestamp: u64,
     pub author: Option<String>,
+    #[serde(default)]
+    pub tripcode: Option<String>,
     pub website: Option<String>,
     pub text: String,
     pub post_index: i64,
     pub reply_to: Option<i64>,
+    /// The markdown the commenter wrote, for editing.
+    pub source: Option<String>,
+    /// The queue `id` the comment had, its commenter's edit cookie refers to it.
+    pub submission: Option<String>,
+    pub approved: Option<u64>,
+    pub edited: Option<u64>,
+    /// A tombstone left by `CommentStore::delete_approved`, only the number and thread remain.
+    #[serde(default)]
+    pub deleted: bool,
 }
 
 #[derive(Default, Serialize, Deserialize)]
@@ -234,12 +628,28 @@ pub struct ApprovedComments {
 #[derive(Default, Serialize, Deserialize)]
 pub struct DisplayComment {
     pub date: String,
+    pub datetime: String,
     pub author: String,
+    pub tripcode: Option<String>,
     pub website: Option<String>,
     pub text: String,
     pub post_index: i64,
     pub reply_to: Option<i64>,
-    pub replies: Vec<i64>
+    /// The comment replied to is not among the published comments, shown at the top level.
+    pub orphan: bool,
+    /// Replies nested under this comment, zero when it has none or they are shown flat after it.
+    pub reply_count: usize,
+    pub collapsed: bool,
+    /// How many reply branches end after this comment.
+    pub closes: usize,
+    pub edited: bool,
+    pub deleted: bool,
+    /// Not submitted yet, shown by `comment_preview` without a number.
+    pub preview: bool,
+    /// The submission ID when the reader wrote this comment and can still edit it.
+    pub editable: Option<String>,
+    /// Later comments that reference this one with `>>N`.
+    pub backlinks: Vec<i64>,
 }
 
 #[derive(Default, Clone, Serialize, Deserialize)]
@@ -247,102 +657,661 @@ pub struct RecentComments {
     pub recent_comments: Vec<String>,
 }
 
-fn layout_template(data: &web::Data<AppState>) -> LayoutTemplate {
-    let rng = get_random(&data.rng) as usize;
+fn request_locale<'a>(req: &web::HttpRequest, data: &'a AppState) -> &'a str {
+    let accepted = req.headers().get("Accept-Language").and_then(|h| h.to_str().ok()).unwrap_or_default();
+    for lang in accepted.split(',') {
+        let lang = lang.split(';').next().unwrap_or_default().trim().to_lowercase();
+        let primary = lang.split('-').next().unwrap_or_default();
+        for candidate in &[lang.as_str(), primary] {
+            if let Some((locale, _)) = data.messages.get_key_value(*candidate) {
+                return locale;
+            }
+        }
+    }
+    &data.config.locale
+}
+
+fn request_messages<'a>(req: &web::HttpRequest, data: &'a AppState) -> &'a Messages {
+    &data.messages[request_locale(req, data)]
+}
+
+/// Terminal clients get plain text: anything preferring text/plain over HTML, and curl or wget unless they ask for HTML.
+fn wants_plain_text(req: &web::HttpRequest) -> bool {
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    let accept = header(actix_web::http::header::ACCEPT);
+    let agent = header(actix_web::http::header::USER_AGENT).to_lowercase();
+    if accept.contains("text/html") {
+        false
+    } else {
+        accept.contains("text/plain") || agent.starts_with("curl/") || agent.starts_with("wget/")
+    }
+}
+
+fn plain_text_context<'a>(req: &web::HttpRequest, data: &'a AppState, meta: &'a Meta) -> plaintext::Context<'a> {
+    plaintext::Context {
+        meta,
+        msg: request_messages(req, data),
+        timezone: &data.timezone,
+        base_url: &data.config.base_url,
+    }
+}
+
+/// Pages that can be served as either HTML or plain text.
+fn negotiated_response(req: &web::HttpRequest, html: impl FnOnce() -> String, text: impl FnOnce() -> String) -> actix_web::HttpResponse {
+    let mut response = actix_web::HttpResponse::Ok();
+    response.header(actix_web::http::header::VARY, "Accept, User-Agent");
+    if wants_plain_text(req) {
+        response.content_type("text/plain; charset=utf-8").body(text())
+    } else {
+        response.body(html())
+    }
+}
+
+fn layout_template<'a>(req: &web::HttpRequest, data: &'a web::Data<AppState>) -> LayoutTemplate<'a> {
+    use chrono::Datelike;
     let quotes_length = data.quote_data.quotes.len();
-    let quote_index = rng % quotes_length;
+    let quote_index = match data.config.quote_mode {
+        QuoteMode::Random => rand::thread_rng().gen_range(0, quotes_length),
+        QuoteMode::Daily => today(&data.timezone).naive_local().num_days_from_ce() as usize % quotes_length,
+    };
+    let meta = data.meta();
     LayoutTemplate {
-        quote_text: &data.quote_data.quotes[quote_index].text,
-        quote_author: &data.quote_data.quotes[quote_index].author,
-        tags: &data.meta.tags,
-        recent_comments: data.recent_comments.read().unwrap().toml.recent_comments.iter().map(|rc| data.meta.articles_map.get(rc).unwrap().0.clone()).collect(),
-        recent_articles: data.meta.recent_articles.iter().take(6).map(|i| i.clone()).collect(),
+        locale: request_locale(req, data),
+        msg: request_messages(req, data),
+        timezone: data.timezone,
+        quote: &data.quote_data.quotes[quote_index],
+        recent_comments: data.recent_comments.read().unwrap().toml.recent_comments.iter()
+            .filter_map(|rc| meta.articles_map.get(rc))
+            .map(|(a, _)| a)
+            .filter(|a| a.is_public())
+            .cloned().collect(),
+        recent_articles: meta.recent_articles.iter().take(6).cloned().collect(),
+        newsletter: data.config.newsletter.is_some(),
+        meta,
     }
 }
 
-async fn p404(data: web::Data<AppState>) -> actix_web::HttpResponse {
+async fn p404(req: web::HttpRequest, data: web::Data<AppState>) -> actix_web::HttpResponse {
     let tmpl = P404Template {
-        layout: layout_template(&data),
+        layout: layout_template(&req, &data),
     };
     actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
 }
 
-async fn index(data: web::Data<AppState>) -> impl Responder {
-    let tmpl = IndexTemplate {
-        layout: layout_template(&data),
-        articles: &data.meta.recent_articles,
-    };
-    actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
+async fn index(req: web::HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let meta = data.meta();
+    negotiated_response(&req,
+        || IndexTemplate {
+            layout: layout_template(&req, &data),
+            articles: &meta.recent_articles,
+        }.render().unwrap(),
+        || plaintext::index(&plain_text_context(&req, &data, &meta), &meta.recent_articles))
 }
 
-async fn article(web::Path(name): web::Path<String>, data: web::Data<AppState>) -> impl Responder {
-    match data.meta.articles_map.get(&name) {
-        Some((a, md)) => {
-            let comments: ApprovedComments = read_toml_default(&comments_path(&name));
-            let mut display_comments: Vec<DisplayComment> = Vec::with_capacity(comments.comments.len());
-            for c in comments.comments {
-                match c.reply_to {
-                    None => (),
-                    Some(parent) =>
-                        match display_comments.iter_mut().find(|dc| dc.post_index == parent) {
-                            None => (),
-                            Some(parent_post) => parent_post.replies.push(c.post_index),
-                        }
+/// Looks up an article, drafts are only found with admin credentials.
+fn find_article<'a>(req: &web::HttpRequest, data: &AppState, meta: &'a Meta, name: &str) -> Option<&'a (Article, String)> {
+    meta.articles_map.get(name).filter(|(a, _)| !a.draft || auth_check(req, &data.admin_password))
+}
+
+async fn article(req: web::HttpRequest, web::Path(name): web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let meta = data.meta();
+    if meta.articles_map.contains_key(&name) && find_article(&req, &data, &meta, &name).is_none() {
+        return unauthorized();
+    }
+    match find_article(&req, &data, &meta, &name) {
+        Some((a, _)) if !is_unlocked(&req, &data.cookie_key, a) => negotiated_response(&req,
+            || ArticleUnlockTemplate {
+                layout: layout_template(&req, &data),
+                article: a,
+                wrong_passphrase: false,
+            }.render().unwrap(),
+            || plaintext::locked(&plain_text_context(&req, &data, &meta), a)),
+        Some((a, md)) if a.is_public() && data.activitypub.is_some() && activitypub::wants_activity_json(&req) =>
+            activitypub::article(data.activitypub.as_ref().unwrap(), a, md),
+        Some((a, md)) => negotiated_response(&req,
+            || ArticleTemplate {
+                layout: layout_template(&req, &data),
+                article: a,
+                content: md, 
+                comments: display_comments(&req, &data, &name),
+                pending: comment_edit::pending(&req, &data, &name),
+                mentions: read_toml_default::<webmention::Mentions>(&mentions_path(&name)).mentions,
+                json_ld: article_json_ld(a, &meta, &data.config),
+                translations: match a.translation.as_ref().and_then(|t| meta.translations.get(t)) {
+                    None => Vec::new(),
+                    Some(ts) => ts.iter().filter(|t| t.name != a.name).cloned().collect(),
+                },
+            }.render().unwrap(),
+            || plaintext::article(&plain_text_context(&req, &data, &meta), a, &data.comments.approved(&name))),
+        None => p404(req, data).await
+    }
+}
+
+/// A single comment, as a page of its own or with `?fragment` as the HTML shown when hovering a `>>N` reference.
+async fn comment_permalink(req: web::HttpRequest, web::Path((name, index)): web::Path<(String, i64)>, data: web::Data<AppState>) -> web::HttpResponse {
+    let meta = data.meta();
+    let found = find_article(&req, &data, &meta, &name)
+        .filter(|(a, _)| is_unlocked(&req, &data.cookie_key, a))
+        .and_then(|(a, _)| display_comments(&req, &data, &name).into_iter().find(|c| c.post_index == index).map(|c| (a, c)));
+    match found {
+        Some((article, comment)) if req.query_string().split('&').any(|p| p == "fragment") =>
+            actix_web::HttpResponse::Ok().body(CommentFragmentTemplate {
+                layout: layout_template(&req, &data),
+                article,
+                comment,
+            }.render().unwrap()),
+        Some((article, comment)) =>
+            actix_web::HttpResponse::Ok().body(CommentTemplate {
+                layout: layout_template(&req, &data),
+                article,
+                comment,
+            }.render().unwrap()),
+        None => p404(req, data).await,
+    }
+}
+
+fn display_comments(req: &web::HttpRequest, data: &AppState, name: &str) -> Vec<DisplayComment> {
+    let msg = request_messages(req, data);
+    let mut comments = data.comments.approved(name);
+    comments.sort_by_key(|c| c.post_index);
+    let owned = comment_edit::owned_ids(req, &data.cookie_key);
+    let positions: HashMap<i64, usize> = comments.iter().enumerate().map(|(i, c)| (c.post_index, i)).collect();
+
+    let mut backlinks: Vec<Vec<i64>> = vec![Vec::new(); comments.len()];
+    for c in comments.iter_mut() {
+        let own_index = c.post_index;
+        let (text, references) = link_comment_references(&c.text, name, |n| n < own_index && positions.contains_key(&n));
+        c.text = text;
+        for n in references {
+            backlinks[positions[&n]].push(own_index);
+        }
+    }
+
+    let parents: Vec<Option<usize>> = comments.iter().map(|c| c.reply_to.and_then(|parent| positions.get(&parent).copied())).collect();
+    let orphans: Vec<bool> = comments.iter().zip(&parents).map(|(c, parent)| c.reply_to.is_some() && parent.is_none()).collect();
+    let thread = CommentThread::new(&parents, data.config.comment_threads);
+
+    let mut comments: Vec<Option<ApprovedComment>> = comments.into_iter().map(Some).collect();
+    thread.order.into_iter().map(|entry| {
+        let c = comments[entry.index].take().unwrap();
+        let editable = c.submission.clone().filter(|id| !c.deleted && owned.contains(id) && comment_edit::within_window(&c, &data.config));
+        DisplayComment {
+            author: author_name_fn(&c.author, &msg.anon),
+            tripcode: c.tripcode,
+            website: c.website,
+            date: timestamp_to_datestring(&c.timestamp, &data.timezone, &msg.date_format),
+            datetime: timestamp_to_datestring(&c.timestamp, &data.timezone, "%Y-%m-%dT%H:%M:%S%:z"),
+            reply_to: c.reply_to,
+            post_index: c.post_index,
+            text: c.text,
+            orphan: orphans[entry.index],
+            reply_count: entry.reply_count,
+            collapsed: entry.reply_count > data.config.comment_threads.collapse_after,
+            closes: entry.closes,
+            edited: c.edited.is_some(),
+            deleted: c.deleted,
+            preview: false,
+            editable,
+            backlinks: std::mem::take(&mut backlinks[entry.index]),
+        }
+    }).collect()
+}
+
+/// Turns `>>N` in comment HTML into links to comment N when `exists(N)`, leaving code and
+/// existing links alone. Returns the new HTML and the referenced comments, each once.
+fn link_comment_references(html: &str, article: &str, exists: impl Fn(i64) -> bool) -> (String, Vec<i64>) {
+    const MARKER: &str = "&gt;&gt;";
+    let mut out = String::with_capacity(html.len());
+    let mut references = Vec::new();
+    let mut verbatim = 0;
+    let mut rest = html;
+    while !rest.is_empty() {
+        if rest.starts_with('<') {
+            let end = rest.find('>').map(|i| i + 1).unwrap_or_else(|| rest.len());
+            let tag = rest[1..end].trim_end_matches('>');
+            let name = tag.trim_start_matches('/').split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or_default();
+            if name == "a" || name == "code" || name == "pre" {
+                if tag.starts_with('/') { verbatim = (verbatim - 1).max(0) } else { verbatim += 1 }
+            }
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+        if verbatim == 0 && rest.starts_with(MARKER) {
+            let digits = rest[MARKER.len()..].chars().take_while(char::is_ascii_digit).count();
+            if let Ok(n) = rest[MARKER.len()..MARKER.len() + digits].parse::<i64>() {
+                if exists(n) {
+                    out.push_str(&format!("<a class=\"comment_ref\" href=\"/a/{}#{}\" data-comment=\"{}\">&gt;&gt;{}</a>", article, n, n, n));
+                    if !references.contains(&n) {
+                        references.push(n);
+                    }
+                    rest = &rest[MARKER.len() + digits..];
+                    continue;
                 }
-                display_comments.push(DisplayComment {
-                    author: author_name_fn(&c.author),
-                    website: c.website,
-                    date: timestamp_to_datestring(&c.timestamp),
-                    reply_to: c.reply_to,
-                    post_index: c.post_index,
-                    text: c.text,
-                    replies: Vec::new(),
+            }
+        }
+        let c = rest.chars().next().unwrap();
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    (out, references)
+}
+
+/// Comment positions in the order `article.html` renders them, replies right after their parent.
+struct CommentThread {
+    children: Vec<Vec<usize>>,
+    descendants: Vec<usize>,
+    config: CommentThreadsConfig,
+    order: Vec<ThreadEntry>,
+}
+
+struct ThreadEntry {
+    index: usize,
+    reply_count: usize,
+    closes: usize,
+}
+
+impl CommentThread {
+    /// Orders comments by the position of each one's parent. Replies to a later comment, which
+    /// only hand-edited files can have, are shown at the top level like replies to missing ones.
+    fn new(parents: &[Option<usize>], config: CommentThreadsConfig) -> CommentThread {
+        let mut thread = CommentThread {
+            children: vec![Vec::new(); parents.len()],
+            descendants: vec![0; parents.len()],
+            config,
+            order: Vec::with_capacity(parents.len()),
+        };
+        let mut roots = Vec::new();
+        for (i, parent) in parents.iter().enumerate() {
+            match parent {
+                Some(parent) if *parent < i => thread.children[*parent].push(i),
+                _ => roots.push(i),
+            }
+        }
+        for i in (0..parents.len()).rev() {
+            thread.descendants[i] = thread.children[i].iter().map(|&c| 1 + thread.descendants[c]).sum();
+        }
+        for root in roots {
+            thread.push(root, 0);
+        }
+        thread
+    }
+
+    fn push(&mut self, i: usize, depth: usize) {
+        if self.children[i].is_empty() || depth + 1 >= self.config.max_depth {
+            self.order.push(ThreadEntry { index: i, reply_count: 0, closes: 0 });
+            let mut flat = Vec::with_capacity(self.descendants[i]);
+            self.collect_descendants(i, &mut flat);
+            flat.sort_unstable();
+            self.order.extend(flat.into_iter().map(|index| ThreadEntry { index, reply_count: 0, closes: 0 }));
+            return;
+        }
+        self.order.push(ThreadEntry { index: i, reply_count: self.descendants[i], closes: 0 });
+        for child in self.children[i].clone() {
+            self.push(child, depth + 1);
+        }
+        self.order.last_mut().unwrap().closes += 1;
+    }
+
+    fn collect_descendants(&self, i: usize, out: &mut Vec<usize>) {
+        for &child in &self.children[i] {
+            out.push(child);
+            self.collect_descendants(child, out);
+        }
+    }
+}
+
+/// schema.org data for the article, a BlogPosting and for book reviews also a Review of the book.
+fn article_json_ld(article: &Article, meta: &Meta, config: &Config) -> String {
+    let url = format!("{}/a/{}", config.base_url, article.name);
+    let authors: Vec<serde_json::Value> = article.author.iter().map(|slug| serde_json::json!({
+        "@type": "Person",
+        "name": meta.authors[slug].name,
+        "url": format!("{}/author/{}", config.base_url, slug),
+    })).collect();
+    let mut graph = vec![serde_json::json!({
+        "@type": "BlogPosting",
+        "@id": url,
+        "url": url,
+        "headline": article.title,
+        "description": article.description,
+        "datePublished": atom_date(&article.date),
+        "inLanguage": article.lang,
+        "keywords": article.tags,
+        "author": authors,
+    })];
+    match &article.review {
+        Some(review) if article.tags.iter().any(|t| t == "Book") => {
+            let mut book = serde_json::json!({ "@type": "Book", "name": review.item });
+            if let Some(name) = &review.item_author {
+                book["author"] = serde_json::json!({ "@type": "Person", "name": name });
+            }
+            if let Some(isbn) = &review.isbn {
+                book["isbn"] = serde_json::json!(isbn);
+            }
+            let mut node = serde_json::json!({
+                "@type": "Review",
+                "url": url,
+                "name": article.title,
+                "datePublished": atom_date(&article.date),
+                "author": authors,
+                "itemReviewed": book,
+            });
+            if let Some(rating) = review.rating {
+                node["reviewRating"] = serde_json::json!({
+                    "@type": "Rating",
+                    "ratingValue": rating,
+                    "bestRating": review.best_rating,
+                    "worstRating": 1,
                 });
             }
-            let tmpl = ArticleTemplate {
-                layout: layout_template(&data),
-                article: &a,
-                content: &md, 
-                comments: display_comments,
+            graph.push(node);
+        },
+        _ => (),
+    }
+    let json = serde_json::json!({ "@context": "https://schema.org", "@graph": graph }).to_string();
+    // Keep a `</script>` inside a string from ending the script element.
+    json.replace("</", "<\\/")
+}
+
+/// The markdown source with the article metadata as TOML front matter.
+fn article_source(article: &Article, source: &str) -> String {
+    let mut front = toml::value::Table::new();
+    front.insert("title".to_string(), toml::Value::String(article.title.clone()));
+    front.insert("description".to_string(), toml::Value::String(article.description.clone()));
+    front.insert("date".to_string(), toml::Value::Datetime(article.date.clone()));
+    front.insert("tags".to_string(), toml::Value::Array(article.tags.iter().cloned().map(toml::Value::String).collect()));
+    front.insert("lang".to_string(), toml::Value::String(article.lang.clone()));
+    if !article.author.is_empty() {
+        front.insert("author".to_string(), toml::Value::Array(article.author.iter().cloned().map(toml::Value::String).collect()));
+    }
+    if let Some(t) = &article.translation {
+        front.insert("translation".to_string(), toml::Value::String(t.clone()));
+    }
+    format!("+++\n{}+++\n\n{}", toml::to_string(&front).unwrap(), source)
+}
+
+async fn article_markdown(req: web::HttpRequest, web::Path(name): web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let meta = data.meta();
+    match find_article(&req, &data, &meta, &name) {
+        Some((a, _)) if is_unlocked(&req, &data.cookie_key, a) => actix_web::HttpResponse::Ok()
+            .content_type("text/markdown; charset=utf-8")
+            .body(article_source(a, &meta.sources[&name])),
+        _ => p404(req, data).await,
+    }
+}
+
+async fn article_print(req: web::HttpRequest, web::Path(name): web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let meta = data.meta();
+    match find_article(&req, &data, &meta, &name) {
+        Some((a, _)) if !is_unlocked(&req, &data.cookie_key, a) => {
+            let tmpl = ArticleUnlockTemplate {
+                layout: layout_template(&req, &data),
+                article: a,
+                wrong_passphrase: false,
             };
             actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
         },
-        None => p404(data).await
+        Some((a, md)) => {
+            let tmpl = ArticlePrintTemplate {
+                layout: layout_template(&req, &data),
+                article: a,
+                content: md,
+                comments: display_comments(&req, &data, &name),
+            };
+            actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
+        },
+        None => p404(req, data).await
+    }
+}
+
+#[derive(Deserialize)]
+struct UnlockForm {
+    passphrase: String,
+}
+
+fn unlock_cookie_name(article: &Article) -> String {
+    format!("unlock_{}", article.name)
+}
+
+/// The unlock cookie's value, derived from the passphrase so that changing it locks the article
+/// again for everyone.
+fn unlock_token(key: &actix_web::cookie::Key, article: &Article, passphrase: &str) -> String {
+    let hmac_key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key.signing());
+    let tag = ring::hmac::sign(&hmac_key, format!("{}\0{}", article.name, passphrase).as_bytes());
+    base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+}
+
+fn is_unlocked(req: &web::HttpRequest, key: &actix_web::cookie::Key, article: &Article) -> bool {
+    match &article.access {
+        Access::Public => true,
+        Access::Private { passphrase } => {
+            let name = unlock_cookie_name(article);
+            match req.cookie(&name) {
+                None => false,
+                Some(cookie) => {
+                    let mut jar = actix_web::cookie::CookieJar::new();
+                    jar.add_original(cookie);
+                    jar.signed(key).get(&name).is_some_and(|c| ring::constant_time::verify_slices_are_equal(
+                        c.value().as_bytes(), unlock_token(key, article, passphrase).as_bytes()).is_ok())
+                }
+            }
+        }
+    }
+}
+
+async fn article_unlock(req: web::HttpRequest, web::Path(name): web::Path<String>, web::Form(form): web::Form<UnlockForm>, data: web::Data<AppState>) -> web::HttpResponse {
+    let meta = data.meta();
+    match find_article(&req, &data, &meta, &name) {
+        Some((a, _)) => match &a.access {
+            Access::Private { passphrase } if ring::constant_time::verify_slices_are_equal(form.passphrase.as_bytes(), passphrase.as_bytes()).is_ok() => {
+                let mut jar = actix_web::cookie::CookieJar::new();
+                jar.signed(&data.cookie_key).add(
+                    actix_web::cookie::Cookie::build(unlock_cookie_name(a), unlock_token(&data.cookie_key, a, passphrase))
+                        .path("/")
+                        .http_only(true)
+                        .same_site(actix_web::cookie::SameSite::Lax)
+                        .finish());
+                actix_web::HttpResponse::Found()
+                    .cookie(jar.get(&unlock_cookie_name(a)).unwrap().clone())
+                    .header(actix_web::http::header::LOCATION, format!("/a/{}", name)).finish()
+            },
+            Access::Private { .. } => {
+                let tmpl = ArticleUnlockTemplate {
+                    layout: layout_template(&req, &data),
+                    article: a,
+                    wrong_passphrase: true,
+                };
+                actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
+            },
+            Access::Public => actix_web::HttpResponse::Found()
+                .header(actix_web::http::header::LOCATION, format!("/a/{}", name)).finish(),
+        },
+        None => p404(req, data).await
     }
 }
 
-async fn tag(web::Path(name): web::Path<String>, data: web::Data<AppState>) -> impl Responder {
-    match data.meta.tags.iter().find(|&x| x.name == name) {
-        Some(tag_meta) => {
-            let tmpl = TagTemplate {
-                layout: layout_template(&data),
-                tag: tag_meta,
-                articles: tag_meta.articles.iter().map(|t| data.meta.articles_map[t].0.clone()).collect(),
+async fn lang_index(req: web::HttpRequest, web::Path(lang): web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let meta = data.meta();
+    match meta.articles_by_lang.get(&lang) {
+        Some(articles) => {
+            let tmpl = IndexTemplate {
+                layout: layout_template(&req, &data),
+                articles,
             };
             actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
         },
-        None => p404(data).await
+        None => p404(req, data).await
     }
 }
 
-async fn about(data: web::Data<AppState>) -> impl Responder {
+async fn lang_feed(req: web::HttpRequest, web::Path(lang): web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let meta = data.meta();
+    match meta.articles_by_lang.get(&lang) {
+        Some(articles) => {
+            let tmpl = FeedTemplate {
+                title: &lang,
+                base_url: &data.config.base_url,
+                path: &format!("/lang/{}/feed.xml", lang),
+                articles,
+                authors: &meta.authors,
+            };
+            actix_web::HttpResponse::Ok().content_type("application/atom+xml").body(tmpl.render().unwrap())
+        },
+        None => p404(req, data).await
+    }
+}
+
+/// Resolves `a+b` style tag names to the tags they refer to, and whether any of the names was an alias.
+fn resolve_tags<'a>(meta: &'a Meta, name: &str) -> Option<(Vec<&'a Tag>, bool)> {
+    let mut tags: Vec<&Tag> = Vec::new();
+    let mut aliased = false;
+    for part in name.split('+') {
+        match meta.tags.iter().find(|t| t.name == part) {
+            Some(t) => tags.push(t),
+            None => {
+                tags.push(meta.tags.iter().find(|t| t.aliases.iter().any(|a| a == part))?);
+                aliased = true;
+            }
+        }
+    }
+    Some((tags, aliased))
+}
+
+fn tagged_articles<'a>(meta: &'a Meta, tags: &[&'a Tag]) -> Vec<&'a Article> {
+    meta.recent_articles.iter().filter(|a| tags.iter().all(|t| t.articles.contains(&a.name))).collect()
+}
+
+async fn tag(req: web::HttpRequest, web::Path(name): web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let meta = data.meta();
+    // `/tag/a+b` shows the articles that are in both a and b.
+    let (tags, aliased) = match resolve_tags(&meta, &name) {
+        Some(resolved) => resolved,
+        None => return p404(req, data).await,
+    };
+    if aliased {
+        let canonical: Vec<&str> = tags.iter().map(|t| t.name.as_str()).collect();
+        return actix_web::HttpResponse::MovedPermanently()
+            .header(actix_web::http::header::LOCATION, format!("/tag/{}", canonical.join("+"))).finish();
+    }
+    let articles = tagged_articles(&meta, &tags);
+    negotiated_response(&req,
+        || TagTemplate {
+            layout: layout_template(&req, &data),
+            articles: articles.iter().cloned().cloned().collect(),
+            tags: tags.clone(),
+        }.render().unwrap(),
+        || plaintext::tag(&plain_text_context(&req, &data, &meta), &tags, &articles))
+}
+
+/// Packages public articles, optionally only those in `tag` (which may be an `a+b` intersection), as an EPUB.
+fn export_epub(meta: &Meta, config: &Config, tag: Option<&str>) -> Option<Vec<u8>> {
+    let (title, path, articles) = match tag {
+        None => ("Lesser Scholar".to_string(), "/export/blog.epub".to_string(), meta.recent_articles.iter().collect()),
+        Some(name) => {
+            let (tags, _) = resolve_tags(meta, name)?;
+            let names: Vec<&str> = tags.iter().map(|t| t.name.as_str()).collect();
+            (format!("Lesser Scholar: {}", names.join(" + ")), format!("/tag/{}.epub", names.join("+")), tagged_articles(meta, &tags))
+        }
+    };
+    // Oldest first reads better as a book.
+    let articles: Vec<&Article> = articles.into_iter().rev().collect();
+    let identifier = format!("{}{}", config.base_url, path);
+    let out = epub::write_epub(std::io::Cursor::new(Vec::new()), &identifier, &title, &config.locale, &articles, meta).unwrap();
+    Some(out.into_inner())
+}
+
+fn epub_response(book: Vec<u8>, filename: &str) -> actix_web::HttpResponse {
+    actix_web::HttpResponse::Ok()
+        .content_type("application/epub+zip")
+        .header(actix_web::http::header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename))
+        .body(book)
+}
+
+async fn blog_epub(data: web::Data<AppState>) -> impl Responder {
+    let meta = data.meta();
+    epub_response(export_epub(&meta, &data.config, None).unwrap(), "lesser-scholar.epub")
+}
+
+async fn tag_epub(req: web::HttpRequest, web::Path(name): web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let meta = data.meta();
+    match export_epub(&meta, &data.config, Some(&name)) {
+        Some(book) => epub_response(book, &format!("lesser-scholar-{}.epub", name)),
+        None => p404(req, data).await
+    }
+}
+
+async fn author(req: web::HttpRequest, web::Path(slug): web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let meta = data.meta();
+    match meta.authors.get(&slug) {
+        Some(author) => {
+            let tmpl = AuthorTemplate {
+                layout: layout_template(&req, &data),
+                author,
+                articles: &meta.articles_by_author[&slug],
+            };
+            actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
+        },
+        None => p404(req, data).await
+    }
+}
+
+async fn author_feed(req: web::HttpRequest, web::Path(slug): web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let meta = data.meta();
+    match meta.authors.get(&slug) {
+        Some(author) => {
+            let tmpl = FeedTemplate {
+                title: &author.name,
+                base_url: &data.config.base_url,
+                path: &format!("/author/{}/feed.xml", slug),
+                articles: &meta.articles_by_author[&slug],
+                authors: &meta.authors,
+            };
+            actix_web::HttpResponse::Ok().content_type("application/atom+xml").body(tmpl.render().unwrap())
+        },
+        None => p404(req, data).await
+    }
+}
+
+async fn quote_list(req: web::HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let tmpl = QuotesTemplate {
+        layout: layout_template(&req, &data),
+        quotes: &data.quote_data.quotes,
+    };
+    actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
+}
+
+async fn quote(req: web::HttpRequest, web::Path(id): web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    match data.quote_data.quotes.iter().find(|q| q.id == id) {
+        Some(permalink) => {
+            let tmpl = QuoteTemplate {
+                layout: layout_template(&req, &data),
+                permalink,
+            };
+            actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
+        },
+        None => p404(req, data).await
+    }
+}
+
+async fn about(req: web::HttpRequest, data: web::Data<AppState>) -> impl Responder {
     let tmpl = AboutTemplate {
-        layout: layout_template(&data),
+        layout: layout_template(&req, &data),
     };
     actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
 }
 
-async fn archive(data: web::Data<AppState>) -> impl Responder {
+async fn archive(req: web::HttpRequest, data: web::Data<AppState>) -> impl Responder {
     let tmpl = ArchiveTemplate {
-        layout: layout_template(&data),
+        layout: layout_template(&req, &data),
     };
     actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
 }
 
-async fn error(error: &str, data: web::Data<AppState>) -> web::HttpResponse {
+async fn error(req: &web::HttpRequest, error: &str, data: &web::Data<AppState>) -> web::HttpResponse {
     let tmpl = ErrorTemplate {
-        layout: layout_template(&data),
-        error: error,
+        layout: layout_template(req, data),
+        error,
     };
     actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
 }
@@ -354,18 +1323,39 @@ struct CommentForm {
     website: String,
 }
 
-fn timestamp_to_datestring(timestamp: &u64) -> String {
-    chrono::NaiveDateTime::from_timestamp(timestamp.clone() as i64, 0).format("%Y-%m-%d").to_string()
+fn timestamp_to_datestring(timestamp: &u64, tz: &chrono_tz::Tz, format: &str) -> String {
+    use chrono::TimeZone;
+    tz.timestamp(*timestamp as i64, 0).format(format).to_string()
+}
+
+fn atom_date(date: &toml::value::Datetime) -> String {
+    let date_str = date.to_string();
+    match chrono::DateTime::parse_from_rfc3339(&date_str) {
+        Ok(dt) => dt.to_rfc3339(),
+        Err(_) => format!("{}T00:00:00Z", date_str),
+    }
+}
+
+fn format_article_date(date: &toml::value::Datetime, tz: &chrono_tz::Tz, format: &str) -> String {
+    let date_str = date.to_string();
+    // Dates with a time and offset are shown in the display timezone, plain dates are shown as written.
+    match chrono::DateTime::parse_from_rfc3339(&date_str) {
+        Ok(dt) => dt.with_timezone(tz).format(format).to_string(),
+        Err(_) => match chrono::NaiveDate::parse_from_str(&date_str, "%Y-%m-%d") {
+            Ok(d) => d.format(format).to_string(),
+            Err(_) => date_str,
+        }
+    }
 }
 
 fn extract_parent_post(text: String) -> (String, Option<i64>) {
     let t = text.trim();
-    if t.starts_with("@") {
+    if let Some(rest) = t.strip_prefix('@') {
         let chrs: &[char] = &[' ', '\n', '\t', '\r'];
-        let num_str = (&t[1..]).split(chrs).next().unwrap_or_default();
-        match str::parse::<i64>(&num_str) {
+        let num_str = rest.split(chrs).next().unwrap_or_default();
+        match str::parse::<i64>(num_str) {
             Ok(d) => {
-                (t[1..].trim_start_matches(|c| (c >= '0' && c <= '9') || c == ' ' || c == '\t' || c == '\r' || c == '\n').to_string(), Some(d))
+                (rest.trim_start_matches(|c: char| c.is_ascii_digit() || c == ' ' || c == '\t' || c == '\r' || c == '\n').to_string(), Some(d))
             },
             Err(_) => 
                 (t.to_string(), None)
@@ -380,32 +1370,108 @@ fn append_to_file(path: &str, content: &str) {
     write!(file, "{}", content).unwrap();
 }
 
-async fn comment(web::Path(name): web::Path<String>, web::Form(form): web::Form<CommentForm>, data: web::Data<AppState>) -> impl Responder {
+async fn comment(req: web::HttpRequest, web::Path(name): web::Path<String>, web::Form(form): web::Form<CommentForm>, data: web::Data<AppState>) -> impl Responder {
+    let meta = data.meta();
+    match meta.articles_map.get(&name) {
+        Some((a, _)) if !a.draft && is_unlocked(&req, &data.cookie_key, a) => (),
+        _ => return p404(req, data).await,
+    }
     match (form.author, form.text, form.website) {
         (author, text, website) if author.len() > 100 || text.len() > 10000 || website.len() > 500 =>
-            error("Too long comment or name.", data).await,
+            error(&req, &request_messages(&req, &data).comment_too_long, &data).await,
         (_, text, _) if text.is_empty() => 
-            error("Tried to submit empty comment.", data).await,
+            error(&req, &request_messages(&req, &data).comment_empty, &data).await,
         (author, text, website) => {
             let time = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs();
-            let comment = UncheckedComments { comments: vec!(UncheckedComment {
+            let (author, tripcode) = tripcode::split(&author, &data.tripcode_key);
+            let id = data.comments.submit(UncheckedComment {
+                id: String::new(),
                 timestamp: time,
                 author: if author.is_empty() { None } else { Some(author) },
+                tripcode,
                 website: if website.is_empty() { None } else { Some(website) },
                 article: name.clone(),
-                text: text,
-            })};
-            {
-                let _lock_guard = data.unchecked_comments_file_lock.lock().unwrap();
-                append_to_file(&unverified_comments_path(), &toml::to_string(&comment).unwrap());
-            }
+                text,
+                edit_of: None,
+            });
             actix_web::HttpResponse::Found()
+                .cookie(comment_edit::cookie(&req, &id, &data.cookie_key))
                 .header(actix_web::http::header::LOCATION, format!("/a/{}", name)).finish()
         }
     }
 }
 
-fn html_escape<T: AsRef<str>>(input: T) -> String {
+#[derive(Deserialize)]
+struct CommentPreviewForm {
+    article: String,
+    author: String,
+    text: String,
+    website: String,
+}
+
+/// Shows a comment as it would look once approved, rendered like `make_approved_comment` does.
+/// With `?fragment` only the comment HTML is returned, for the article page's Preview button,
+/// otherwise a page with the preview and the filled-in form, for browsers without JavaScript.
+async fn comment_preview(req: web::HttpRequest, web::Form(form): web::Form<CommentPreviewForm>, data: web::Data<AppState>) -> web::HttpResponse {
+    let meta = data.meta();
+    let article = match meta.articles_map.get(&form.article) {
+        Some((a, _)) if !a.draft && is_unlocked(&req, &data.cookie_key, a) => a,
+        _ => return p404(req, data).await,
+    };
+    let fragment = req.query_string().split('&').any(|p| p == "fragment");
+    if form.author.len() > 100 || form.text.len() > 10000 || form.website.len() > 500 {
+        if fragment {
+            return actix_web::HttpResponse::PayloadTooLarge().body(request_messages(&req, &data).comment_too_long.clone());
+        }
+        return error(&req, &request_messages(&req, &data).comment_too_long, &data).await;
+    }
+    let msg = request_messages(&req, &data);
+    let (author, tripcode) = tripcode::split(&form.author, &data.tripcode_key);
+    let now = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    let c = make_approved_comment(&UncheckedComment {
+        timestamp: now,
+        author: if author.is_empty() { None } else { Some(author) },
+        tripcode,
+        website: if form.website.is_empty() { None } else { Some(form.website.clone()) },
+        text: form.text.clone(),
+        article: article.name.clone(),
+        ..Default::default()
+    }, 0);
+    let existing: Vec<i64> = data.comments.approved(&article.name).iter().map(|c| c.post_index).collect();
+    let comment = DisplayComment {
+        author: author_name_fn(&c.author, &msg.anon),
+        tripcode: c.tripcode,
+        website: c.website,
+        date: timestamp_to_datestring(&c.timestamp, &data.timezone, &msg.date_format),
+        datetime: timestamp_to_datestring(&c.timestamp, &data.timezone, "%Y-%m-%dT%H:%M:%S%:z"),
+        reply_to: c.reply_to,
+        orphan: c.reply_to.is_some_and(|r| !existing.contains(&r)),
+        text: link_comment_references(&c.text, &article.name, |n| existing.contains(&n)).0,
+        preview: true,
+        ..Default::default()
+    };
+    let html = if fragment {
+        CommentFragmentTemplate {
+            layout: layout_template(&req, &data),
+            article,
+            comment,
+        }.render().unwrap()
+    } else {
+        CommentPreviewTemplate {
+            layout: layout_template(&req, &data),
+            article,
+            comment,
+            author: html_escape(&form.author),
+            website: html_escape(&form.website),
+            text: html_escape(&form.text),
+        }.render().unwrap()
+    };
+    actix_web::HttpResponse::Ok().body(html)
+}
+
+/// Removes `<` and `>` from commenter names and websites before they are stored, the stored values
+/// are also shown as plain text, so they are escaped with `html_escape` when put into HTML.
+fn strip_angle_brackets<T: AsRef<str>>(input: T) -> String {
     let mut string = String::with_capacity(input.as_ref().len());
     for c in input.as_ref().chars() {
         if c != '<' && c != '>' {
@@ -415,102 +1481,241 @@ fn html_escape<T: AsRef<str>>(input: T) -> String {
     string
 }
 
+fn html_escape<T: AsRef<str>>(input: T) -> String {
+    let mut string = String::with_capacity(input.as_ref().len());
+    for c in input.as_ref().chars() {
+        match c {
+            '&' => string.push_str("&amp;"),
+            '<' => string.push_str("&lt;"),
+            '>' => string.push_str("&gt;"),
+            '"' => string.push_str("&quot;"),
+            '\'' => string.push_str("&#39;"),
+            c => string.push(c),
+        }
+    }
+    string
+}
+
+/// Whether `ip` is an address on the public internet. Urls from other sites are only fetched from
+/// public addresses, so they can't be used to reach services on this host or its network.
+fn is_public_ip(ip: std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified() || v4.is_broadcast()
+                || v4.is_multicast() || v4.is_documentation() || a == 0 || (a == 100 && b & 0xc0 == 64))
+        },
+        std::net::IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_public_ip(std::net::IpAddr::V4(v4)),
+            None => {
+                let first = v6.segments()[0];
+                // Unique local fc00::/7 and link-local fe80::/10.
+                !(v6.is_loopback() || v6.is_unspecified() || v6.is_multicast() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+            },
+        },
+    }
+}
+
+/// Resolves the host of the http(s) `url` and checks that all of its addresses are public.
+async fn check_public_url(url: &str) -> Result<(), String> {
+    let uri: actix_web::http::Uri = url.parse().map_err(|_| format!("invalid url {}", url))?;
+    let port = match (uri.scheme_str(), uri.port_u16()) {
+        (_, Some(port)) => port,
+        (Some("http"), None) => 80,
+        (Some("https"), None) => 443,
+        _ => return Err(format!("not an http url: {}", url)),
+    };
+    let host = uri.host().ok_or_else(|| format!("no host in {}", url))?
+        .trim_start_matches('[').trim_end_matches(']').to_string();
+    let addresses = web::block(move || std::net::ToSocketAddrs::to_socket_addrs(&(host.as_str(), port)).map(|a| a.collect::<Vec<_>>()))
+        .await.map_err(|e| e.to_string())?;
+    match addresses.iter().find(|a| !is_public_ip(a.ip())) {
+        _ if addresses.is_empty() => Err(format!("{} has no addresses", url)),
+        Some(a) => Err(format!("{} resolves to the non-public address {}", url, a.ip())),
+        None => Ok(()),
+    }
+}
+
+/// Renders comment markdown, keeping `>>N` references as text instead of letting a reference at
+/// the start of a line become a nested blockquote.
+fn comment_markdown_to_html(text: &str) -> String {
+    const PLACEHOLDER: char = '\u{E000}';
+    let mut protected = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find(">>") {
+        protected.push_str(&rest[..i]);
+        if rest[i + 2..].starts_with(|c: char| c.is_ascii_digit()) {
+            protected.push(PLACEHOLDER);
+        } else {
+            protected.push_str(">>");
+        }
+        rest = &rest[i + 2..];
+    }
+    protected.push_str(rest);
+    markdown::to_html(&protected).replace(PLACEHOLDER, "&gt;&gt;")
+}
+
+/// Cleans comment HTML rendered from markdown down to an allowlist of formatting tags, links
+/// only to http, https and mailto URLs and marked `rel="nofollow ugc"`.
+fn sanitize_comment_html(html: &str) -> String {
+    ammonia::Builder::empty()
+        .add_tags(&["a", "p", "br", "hr", "em", "strong", "code", "pre", "blockquote", "ul", "ol", "li",
+            "h1", "h2", "h3", "h4", "h5", "h6"])
+        .add_tag_attributes("a", &["href", "title"])
+        .url_schemes(["http", "https", "mailto"].iter().copied().collect())
+        .link_rel(Some("nofollow ugc"))
+        .clean(html)
+        .to_string()
+}
+
+const ADMIN_SESSION_COOKIE: &str = "admin_session";
+
+/// The random value of the signed admin session cookie, which doubles as the CSRF token of the
+/// moderation form.
+fn admin_session(req: &web::HttpRequest, key: &actix_web::cookie::Key) -> Option<String> {
+    let cookie = req.cookie(ADMIN_SESSION_COOKIE)?;
+    let mut jar = actix_web::cookie::CookieJar::new();
+    jar.add_original(cookie);
+    jar.signed(key).get(ADMIN_SESSION_COOKIE).map(|c| c.value().to_string())
+}
+
+/// The admin session, with the cookie to set when the request didn't have one yet. The cookie
+/// covers `/comment_approval` and the pages under it.
+fn admin_session_or_new(req: &web::HttpRequest, key: &actix_web::cookie::Key) -> (String, Option<actix_web::cookie::Cookie<'static>>) {
+    match admin_session(req, key) {
+        Some(session) => (session, None),
+        None => {
+            let session = format!("{:032x}", rand::random::<u128>());
+            let mut jar = actix_web::cookie::CookieJar::new();
+            jar.signed(key).add(
+                actix_web::cookie::Cookie::build(ADMIN_SESSION_COOKIE, session.clone())
+                    .path("/comment_approval")
+                    .http_only(true)
+                    .same_site(actix_web::cookie::SameSite::Strict)
+                    .finish());
+            (session, jar.get(ADMIN_SESSION_COOKIE).cloned())
+        }
+    }
+}
+
+/// Whether the `csrf` field of an admin form matches the admin session.
+fn csrf_ok(req: &web::HttpRequest, key: &actix_web::cookie::Key, token: Option<&str>) -> bool {
+    matches!((admin_session(req, key), token), (Some(session), Some(token)) if session == token)
+}
+
+const CSRF_PROBLEM: &str = "The form had expired or did not come from this page, nothing was changed. Please check and submit again.";
+
+fn render_comment_approval(req: &web::HttpRequest, data: &web::Data<AppState>, problems: Vec<String>) -> web::HttpResponse {
+    let meta = data.meta();
+    let (session, new_cookie) = admin_session_or_new(req, &data.cookie_key);
+    let comments = data.comments.unchecked();
+    let mut published: Vec<(&Article, usize)> = meta.articles_map.values()
+        .map(|(a, _)| (a, data.comments.approved(&a.name).iter().filter(|c| !c.deleted).count()))
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    published.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+    let tmpl = CommentApprovalsTemplate {
+        layout: layout_template(req, data),
+        comments: comments.iter().map(|c| (sanitize_comment_html(&comment_markdown_to_html(&c.text)), c)).collect(),
+        mentions: webmention::unchecked(&data.mentions_file_lock),
+        published,
+        author_name_fn,
+        html_escape_fn: |s| html_escape(s),
+        problems,
+        csrf_token: &session,
+    };
+    let mut response = actix_web::HttpResponse::Ok();
+    if let Some(cookie) = new_cookie {
+        response.cookie(cookie);
+    }
+    response.body(tmpl.render().unwrap())
+}
+
 async fn comment_approval(req: web::HttpRequest, data: web::Data<AppState>) -> web::HttpResponse {
     if !auth_check(&req, &data.admin_password) {
         return unauthorized();
     }
-    let comments: UncheckedComments = read_toml_default(&unverified_comments_path());
-    let tmpl = CommentApprovalsTemplate {
-        layout: layout_template(&data),
-        comments: comments.comments.iter().map(|c| (markdown::to_html(&c.text), c)).collect(),
-        author_name_fn: author_name_fn,
-    };
-    actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
+    render_comment_approval(&req, &data, Vec::new())
 }
 
-pub fn author_name_fn(author: &Option<String>) -> String {
+pub fn author_name_fn(author: &Option<String>, anon: &str) -> String {
     match author {
-        None => "Anon".to_string(),
+        None => anon.to_string(),
         Some(s) => html_escape(s),
     }
 }
 
-fn make_approved_comment(comment: &UncheckedComment, post_index: i64 ) -> ApprovedComments {
+fn make_approved_comment(comment: &UncheckedComment, post_index: i64 ) -> ApprovedComment {
     let (t, reply_to) = extract_parent_post(comment.text.clone());
+    let now = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs();
     
-    ApprovedComments {
-        comments: vec!(ApprovedComment {
-            timestamp: comment.timestamp,
-            author: comment.author.clone().map(html_escape),
-            website: comment.website.clone().map(html_escape),
-            text: markdown::to_html(&t),
-            post_index: post_index,
-            reply_to: reply_to,
-        })
-    }
-}
-
-fn fetch_incr_count(value: &mut toml::Value, key: &str) -> i64 {
-    match value {
-        toml::Value::Table(t) => {
-            match t.get_mut(key) {
-                Some(v) => {
-                    if let toml::Value::Integer(res) = v {
-                        *res += 1;
-                        *res
-                    } else {
-                        panic!("count was not integer");
-                    }
-                },
-                None => {
-                    t.insert(key.to_string(), toml::Value::Integer(0));
-                    0
-                }
-            }
-        },
-        _ => panic!("unexpected"),
+    ApprovedComment {
+        timestamp: comment.timestamp,
+        source: Some(comment.text.clone()),
+        submission: Some(comment.id.clone()),
+        approved: Some(now),
+        edited: None,
+        deleted: false,
+        author: comment.author.clone().map(strip_angle_brackets),
+        tripcode: comment.tripcode.clone(),
+        website: comment.website.clone().map(strip_angle_brackets),
+        text: sanitize_comment_html(&comment_markdown_to_html(&t)),
+        post_index,
+        reply_to,
     }
 }
 
-async fn comment_approval_post(req: web::HttpRequest, body: String, data: web::Data<AppState>) -> web::HttpResponse {
+/// Handles the moderation form: `csrf`, `c{id}` for comments and `m{id}` for mentions, each
+/// `ignore`, `approve` or `delete`.
+async fn comment_approval_post(req: web::HttpRequest, web::Form(form): web::Form<Vec<(String, String)>>, data: web::Data<AppState>) -> web::HttpResponse {
     if !auth_check(&req, &data.admin_password) {
         return unauthorized();
     }
-    let mut approved_comments: Vec<(String, ApprovedComments)> = Vec::new();
-    {
-        let _lock_guard = data.unchecked_comments_file_lock.lock().unwrap();
-        let comment_list = read_toml::<UncheckedComments>(&unverified_comments_path()).comments;
-        let mut new_unchecked_data = UncheckedComments { comments: Vec::new() };
-        let mut comment_counts: TomlFile<toml::Value> = TomlFile::read(&comment_counts_path());
-    
-        let mut count = 0;
-        for item in body.split('&') {
-            let c = &comment_list[count];
-            match item.split('=').skip(1).next() {
-                Some("ignore") => new_unchecked_data.comments.push(c.clone()),
-                Some("approve") => approved_comments.push((c.article.clone(), make_approved_comment(&c, fetch_incr_count(&mut comment_counts.toml, &c.article)))),
-                Some("delete") => (),
-                _ => unreachable!(),
-            }
-            count += 1;
+    if !csrf_ok(&req, &data.cookie_key, form.iter().find(|(k, _)| k == "csrf").map(|(_, token)| token.as_str())) {
+        return render_comment_approval(&req, &data, vec![CSRF_PROBLEM.to_string()]);
+    }
+
+    let mut problems = Vec::new();
+    let mut decisions: Vec<(String, comment_store::Decision)> = Vec::new();
+    let mut mention_decisions: Vec<(String, &str)> = Vec::new();
+    for (key, value) in &form {
+        if key == "csrf" {
+            continue;
         }
-        for i in count .. comment_list.len() {
-            new_unchecked_data.comments.push(comment_list[i].clone());
+        if let Some(id) = key.strip_prefix('m') {
+            match value.as_str() {
+                "ignore" => (),
+                "approve" | "delete" => mention_decisions.push((id.to_string(), value)),
+                other => problems.push(format!("Unknown action \"{}\" for mention {}, left in the queue.", html_escape(other), html_escape(id))),
+            }
+            continue;
         }
-        if new_unchecked_data.comments.is_empty() {
-            std::fs::write(unverified_comments_path(), "").unwrap();
-        } else {
-            std::fs::write(unverified_comments_path(), toml::to_string(&new_unchecked_data).unwrap()).unwrap();
+        let id = match key.strip_prefix('c') {
+            Some(id) => id,
+            None => {
+                problems.push(format!("Unknown form field \"{}\", ignored.", html_escape(key)));
+                continue;
+            }
+        };
+        match value.as_str() {
+            "ignore" => (),
+            "approve" => decisions.push((id.to_string(), comment_store::Decision::Approve)),
+            "delete" => decisions.push((id.to_string(), comment_store::Decision::Delete)),
+            other => problems.push(format!("Unknown action \"{}\" for comment {}, left in the queue.", html_escape(other), html_escape(id))),
         }
+    }
+    for id in webmention::moderate(&mention_decisions, &data.mentions_file_lock) {
+        problems.push(format!("Mention {} is no longer in the queue, it was probably moderated already.", html_escape(id)));
+    }
 
-        comment_counts.write();
+    let moderation = data.comments.moderate(&decisions, make_approved_comment);
+    for id in &moderation.missing {
+        problems.push(format!("Comment {} is no longer in the queue, it was probably moderated already.", html_escape(id)));
     }
-    std::fs::create_dir_all(&comments_dir()).unwrap();
 
     let mut recent_comments = data.recent_comments.read().unwrap().toml.recent_comments.clone();
 
-    for (key, val) in approved_comments {
-        append_to_file(&comments_path(&key), &toml::to_string(&val).unwrap());
+    for key in moderation.articles {
         if let Some(i) = recent_comments.iter().position(|rc| rc == &key) {
             recent_comments.remove(i);
         }
@@ -521,18 +1726,30 @@ async fn comment_approval_post(req: web::HttpRequest, body: String, data: web::D
     }
     {
         let mut w = data.recent_comments.write().unwrap();
-        w.toml = RecentComments { recent_comments: recent_comments };
+        w.toml = RecentComments { recent_comments };
         w.write();
     }
 
-    comment_approval(req, data).await
+    render_comment_approval(&req, &data, problems)
+}
+
+/// Takes an article off the recent comments list once it has no published comments left.
+fn forget_recent_comments(data: &AppState, article: &str) {
+    if data.comments.approved(article).iter().any(|c| !c.deleted) {
+        return;
+    }
+    let mut w = data.recent_comments.write().unwrap();
+    if let Some(i) = w.toml.recent_comments.iter().position(|rc| rc == article) {
+        w.toml.recent_comments.remove(i);
+        w.write();
+    }
 }
 
 fn base64_char_value(c: char) -> u8 {
     match c {
-        'A'..='Z' => c as u8 - 'A' as u8,
-        'a'..='z' => c as u8 - 'a' as u8 + 26,
-        '0'..='9' => c as u8 - '0' as u8 + 52,
+        'A'..='Z' => c as u8 - b'A',
+        'a'..='z' => c as u8 - b'a' + 26,
+        '0'..='9' => c as u8 - b'0' + 52,
         '+' => 62,
         '/' => 63,
         '=' => 0,
@@ -575,7 +1792,7 @@ fn auth_check(req: &web::HttpRequest, password: &str) -> bool {
         match (s.next(), s.next(), s.next()) {
             (_, _, Some(_)) => { println!("Extra auth string"); false },
             (Some("Basic"), Some(sign), _) => {
-                &base64_decode(sign) == password
+                base64_decode(sign) == password
             },
             _ => { println!("Not basic auth"); false },
         }
@@ -598,53 +1815,121 @@ async fn stats(req: web::HttpRequest, data: web::Data<AppState>) -> actix_web::H
     for d in std::path::Path::new(&logs_path()).read_dir().unwrap() {
         match d {
             Err(_) => (),
-            Ok(dir) => if dir.file_name().to_string_lossy().to_string() != log_date_string(chrono::Utc::today()) {
+            Ok(dir) => if dir.file_name().to_string_lossy() != log_date_string(today(&data.timezone)) {
                 let cl = get_compacted_log(dir.path());
                 compacted = sum_compacted_log(compacted, cl);
             }
         }
     }
     let mut stats: Vec<(String, usize)> = compacted.entries.into_iter().collect();
-    stats.sort_by(|a, b| (b.1).cmp(&a.1));
+    stats.sort_by_key(|s| std::cmp::Reverse(s.1));
     let tmpl = StatsTemplate {
-        layout: layout_template(&data),
+        layout: layout_template(&req, &data),
         stats: &stats,
     };
     actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
 }
 
 
-fn make_meta(meta_file: MetaFile) -> Meta {
-    let articles = HashMap::from_iter(meta_file.articles.clone().into_iter().map(|a| {
+/// Reads `src/meta.toml`, with the drafts created through Micropub in front of its articles.
+fn read_meta_file() -> Result<MetaFile, String> {
+    let mut meta_file: MetaFile = try_read_toml("src/meta.toml")?;
+    meta_file.articles.splice(0..0, micropub::drafts()?);
+    Ok(meta_file)
+}
+
+/// Builds the site's content from `meta.toml`, or describes what in it is inconsistent.
+fn make_meta(meta_file: MetaFile) -> Result<Meta, String> {
+    let sources: HashMap<String, String> = meta_file.articles.iter().map(|a| {
         let file_path = format!("articles/{}.md", a.name);
-        let md = markdown::file_to_html(std::path::Path::new(&file_path)).expect(&format!("Failed to open article {}", a.name));
+        let source = std::fs::read_to_string(&file_path).map_err(|e| format!("Failed to open article {}: {}", a.name, e))?;
+        Ok((a.name.clone(), source))
+    }).collect::<Result<_, String>>()?;
+    let articles = HashMap::from_iter(meta_file.articles.clone().into_iter().map(|a| {
+        let md = markdown::to_html(&sources[&a.name]);
         (a.name.clone(), (a, md))
     }));
-    let projects = HashMap::from_iter(meta_file.projects.into_iter().map(|p| {
-        let file_path = format!("projects/{}.md", p.name);
-        let md = markdown::file_to_html(std::path::Path::new(&file_path)).expect(&format!("Failed to open project {}", p.name));
-        (p.name.clone(), (p, md))
-    }));
-    let mut tags: Vec<Tag> = meta_file.tags.iter().map(|t| Tag {name: t.clone(), count: 0, articles: Vec::new()}).collect();
     for a in &meta_file.articles {
+        if let Some(review) = &a.review {
+            if review.best_rating == 0 {
+                return Err(format!("Review in article {} has best_rating 0", a.name));
+            }
+            if let Some(rating) = review.rating.filter(|&r| r < 1 || r > review.best_rating) {
+                return Err(format!("Review rating {} in article {} is outside 1 to {}", rating, a.name, review.best_rating));
+            }
+        }
+    }
+    let projects = meta_file.projects.into_iter().map(|p| {
+        let file_path = format!("projects/{}.md", p.name);
+        let md = markdown::file_to_html(std::path::Path::new(&file_path)).map_err(|e| format!("Failed to open project {}: {}", p.name, e))?;
+        Ok((p.name.clone(), (p, md)))
+    }).collect::<Result<HashMap<_, _>, String>>()?;
+    let mut tags: Vec<Tag> = meta_file.tags.into_iter().map(|t| match t {
+        TagDef::Name(name) => Tag {name, count: 0, articles: Vec::new(), description: String::new(), aliases: Vec::new(), parent: None, children: Vec::new()},
+        TagDef::Full {name, description, aliases, parent} =>
+            Tag {name, count: 0, articles: Vec::new(), description: markdown::to_html(&description), aliases, parent, children: Vec::new()},
+    }).collect();
+    let parents: Vec<(String, String)> = tags.iter().filter_map(|t| t.parent.clone().map(|p| (t.name.clone(), p))).collect();
+    for (child, parent) in parents {
+        match tags.iter_mut().find(|t| t.name == parent) {
+            None => return Err(format!("Unknown parent tag {} for tag {}", parent, child)),
+            Some(p) => p.children.push(child),
+        }
+    }
+    for a in meta_file.articles.iter().filter(|a| a.is_public()) {
         for t in &a.tags {
-            for tt in &mut tags {
-                if &tt.name == t {
-                    tt.articles.push(a.name.clone());
-                    tt.count += 1;
+            // An article in a child tag also belongs to all of its ancestors.
+            let mut current = Some(t.clone());
+            while let Some(name) = current {
+                let tt = match tags.iter_mut().find(|tt| tt.name == name) {
+                    None => return Err(format!("Unknown tag {} in article {}", name, a.name)),
+                    Some(tt) => tt,
+                };
+                if tt.articles.contains(&a.name) {
                     break;
                 }
+                tt.articles.push(a.name.clone());
+                tt.count += 1;
+                current = tt.parent.clone();
             }
         }
     }
     tags.sort_by_key(|t| -t.count);
 
-    Meta {
+    let authors: HashMap<String, Author> = HashMap::from_iter(meta_file.authors.into_iter().map(|a| {
+        let bio = markdown::to_html(&a.bio);
+        (a.slug.clone(), Author { bio, ..a })
+    }));
+    let mut articles_by_author: HashMap<String, Vec<Article>> = HashMap::from_iter(authors.keys().map(|k| (k.clone(), Vec::new())));
+    for a in &meta_file.articles {
+        for author in &a.author {
+            match articles_by_author.get_mut(author) {
+                None => return Err(format!("Unknown author {} in article {}", author, a.name)),
+                Some(articles) => if a.is_public() { articles.push(a.clone()) },
+            }
+        }
+    }
+
+    let mut translations: HashMap<String, Vec<Article>> = HashMap::new();
+    let mut articles_by_lang: HashMap<String, Vec<Article>> = HashMap::new();
+    for a in meta_file.articles.iter().filter(|a| a.is_public()) {
+        if let Some(t) = &a.translation {
+            translations.entry(t.clone()).or_default().push(a.clone());
+        }
+        articles_by_lang.entry(a.lang.clone()).or_default().push(a.clone());
+    }
+
+    Ok(Meta {
         articles_map: articles,
+        sources,
         projects_map: projects,
-        tags: tags,
-        recent_articles: meta_file.articles
-    }
+        tags,
+        recent_articles: meta_file.articles.into_iter().filter(|a| a.is_public()).collect(),
+        translations,
+        articles_by_lang,
+        authors,
+        articles_by_author,
+    })
 }
 
 fn blog_data_dir() -> String {
@@ -652,28 +1937,48 @@ fn blog_data_dir() -> String {
     path.to_string_lossy().to_string()
 }
 
-fn unverified_comments_path() -> String {
-    let path: std::path::PathBuf = [&blog_data_dir(), "unverified_comments.toml"].iter().collect();
+fn activitypub_followers_path() -> String {
+    let path: std::path::PathBuf = [&blog_data_dir(), "activitypub", "followers.toml"].iter().collect();
     path.to_string_lossy().to_string()
 }
 
-fn comment_counts_path() -> String {
-    let path: std::path::PathBuf = [&blog_data_dir(), "comment_counts.toml"].iter().collect();
+fn activitypub_delivered_path() -> String {
+    let path: std::path::PathBuf = [&blog_data_dir(), "activitypub", "delivered.toml"].iter().collect();
     path.to_string_lossy().to_string()
 }
 
-fn recent_comments_path() -> String {
-    let path: std::path::PathBuf = [&blog_data_dir(), "recent_comments.toml"].iter().collect();
+fn newsletter_subscribers_path() -> String {
+    let path: std::path::PathBuf = [&blog_data_dir(), "newsletter", "subscribers.toml"].iter().collect();
     path.to_string_lossy().to_string()
 }
 
-fn comments_dir() -> String {
-    let path: std::path::PathBuf = [&blog_data_dir(), "comments"].iter().collect();
+fn newsletter_sent_path() -> String {
+    let path: std::path::PathBuf = [&blog_data_dir(), "newsletter", "sent.toml"].iter().collect();
+    path.to_string_lossy().to_string()
+}
+
+fn unchecked_mentions_path() -> String {
+    let path: std::path::PathBuf = [&blog_data_dir(), "unchecked_mentions.toml"].iter().collect();
+    path.to_string_lossy().to_string()
+}
+
+fn mentions_dir() -> String {
+    let path: std::path::PathBuf = [&blog_data_dir(), "mentions"].iter().collect();
+    path.to_string_lossy().to_string()
+}
+
+fn mentions_path(article: &str) -> String {
+    let path: std::path::PathBuf = [&blog_data_dir(), "mentions", &format!("{}.toml", article)].iter().collect();
+    path.to_string_lossy().to_string()
+}
+
+fn recent_comments_path() -> String {
+    let path: std::path::PathBuf = [&blog_data_dir(), "recent_comments.toml"].iter().collect();
     path.to_string_lossy().to_string()
 }
 
-fn comments_path(article: &str) -> String {
-    let path: std::path::PathBuf = [&blog_data_dir(), "comments", &format!("{}.toml", article)].iter().collect();
+fn comments_database_path() -> String {
+    let path: std::path::PathBuf = [&blog_data_dir(), "comments.sqlite"].iter().collect();
     path.to_string_lossy().to_string()
 }
 
@@ -682,12 +1987,16 @@ fn logs_path() -> String {
     path.to_string_lossy().to_string()
 }
 
-fn log_date_string(date: chrono::Date<chrono::Utc>) -> String {
+fn today(tz: &chrono_tz::Tz) -> chrono::Date<chrono_tz::Tz> {
+    chrono::Utc::now().with_timezone(tz).date()
+}
+
+fn log_date_string(date: chrono::Date<chrono_tz::Tz>) -> String {
     date.format("%Y-%m-%d").to_string()
 }
 
-fn log_file(lock: Arc<Mutex<()>>) -> (std::fs::File, chrono::Date<chrono::Utc>) {
-    let today = chrono::Utc::today();
+fn log_file(lock: Arc<Mutex<()>>, tz: &chrono_tz::Tz) -> (std::fs::File, chrono::Date<chrono_tz::Tz>) {
+    let today = today(tz);
     let mut path: std::path::PathBuf = [&blog_data_dir(), "logs", &log_date_string(today)].iter().collect();
     std::fs::create_dir_all(&path).expect("Failed to create log dir");
     let _lock_result = lock.lock();
@@ -702,16 +2011,6 @@ struct TomlFile<T> {
     toml: T,
 }
 
-impl<T> TomlFile<T> where T: serde::de::DeserializeOwned {
-    fn read(path: &str) -> TomlFile<T> {
-        let data = std::fs::read_to_string(path).unwrap_or_default();
-        TomlFile {
-            path: path.to_string(),
-            toml: toml::from_str(&data).unwrap(),
-        }
-    }
-}
-
 impl<T> TomlFile<T> where T: serde::de::DeserializeOwned + Default{
     fn read_default(path: &str) -> TomlFile<T> {
         let data = std::fs::read_to_string(path).unwrap_or_default();
@@ -724,13 +2023,18 @@ impl<T> TomlFile<T> where T: serde::de::DeserializeOwned + Default{
 
 impl<T> TomlFile<T> where T: serde::Serialize {
     fn write(&self) {
-        std::fs::write(&self.path, &toml::to_string(&self.toml).unwrap()).unwrap();
+        std::fs::write(&self.path, toml::to_string(&self.toml).unwrap()).unwrap();
     }
 }
 
 fn read_toml<T: serde::de::DeserializeOwned>(path: &str) -> T {
+    try_read_toml(path).unwrap()
+}
+
+/// Like `read_toml`, for the files that can change while the server is running.
+fn try_read_toml<T: serde::de::DeserializeOwned>(path: &str) -> Result<T, String> {
     let data = std::fs::read_to_string(path).unwrap_or_default();
-    toml::from_str(&data).unwrap()
+    toml::from_str(&data).map_err(|e| format!("Invalid {}: {}", path, e))
 }
 
 fn read_toml_default<T: serde::de::DeserializeOwned + Default>(path: &str) -> T {
@@ -738,6 +2042,13 @@ fn read_toml_default<T: serde::de::DeserializeOwned + Default>(path: &str) -> T
     toml::from_str(&data).unwrap_or_default()
 }
 
+fn validate_quotes(quotes: &Quotes) {
+    assert!(!quotes.quotes.is_empty(), "src/quotes.toml has no quotes");
+    for (i, q) in quotes.quotes.iter().enumerate() {
+        assert!(!quotes.quotes[..i].iter().any(|other| other.id == q.id), "Duplicate quote id {}", q.id);
+    }
+}
+
 fn get_admin_password() -> String {
     if std::path::Path::new("admin_password.txt").exists() {
         std::fs::read_to_string("admin_password.txt").unwrap()
@@ -746,47 +2057,157 @@ fn get_admin_password() -> String {
     }
 }
 
+fn get_micropub_token() -> Option<String> {
+    std::fs::read_to_string("micropub_token.txt").ok().map(|t| t.trim().to_string()).filter(|t| !t.is_empty())
+}
+
+fn get_cookie_key() -> actix_web::cookie::Key {
+    if std::path::Path::new("cookie_secret.txt").exists() {
+        actix_web::cookie::Key::derive_from(std::fs::read_to_string("cookie_secret.txt").unwrap().trim().as_bytes())
+    } else {
+        // Without a configured secret, unlocked articles lock again when the server restarts.
+        actix_web::cookie::Key::generate()
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let quotes: Quotes = read_toml("src/quotes.toml");
-    let meta = make_meta(read_toml("src/meta.toml"));
+    validate_quotes(&quotes);
+    let config: Config = read_toml("src/config.toml");
+    let timezone: chrono_tz::Tz = config.timezone.parse().expect("Unknown timezone in config");
+    let messages: HashMap<String, Messages> = read_toml("src/messages.toml");
+    assert!(messages.contains_key(&config.locale), "No messages for configured locale {}", config.locale);
+    let meta = read_meta_file().and_then(make_meta).unwrap_or_else(|e| {
+        eprintln!("Content error: {}", e);
+        std::process::exit(1);
+    });
+
+    // `blog --epub=book.epub [--epub_tag=Web]` writes an EPUB and exits instead of serving.
+    let arg = |prefix: &str| std::env::args().find_map(|a| a.strip_prefix(prefix).map(|v| v.to_string()));
+    if let Some(path) = arg("--epub=") {
+        let tag = arg("--epub_tag=");
+        let book = export_epub(&meta, &config, tag.as_deref()).expect("Unknown tag");
+        return std::fs::write(path, book);
+    }
+    // `blog --import-comments` copies the TOML comment files into the SQLite comment store and exits.
+    if std::env::args().any(|a| a == "--import-comments") {
+        let (unchecked, approved) = comment_store::SqliteStore::open(&comments_database_path()).import_toml(&blog_data_dir())
+            .expect("Failed to import comments");
+        println!("Imported {} queued and {} published comments into {}", unchecked, approved, comments_database_path());
+        return Ok(());
+    }
+    // `blog --resanitize-comments` runs published comments through the current sanitizer and exits.
+    if std::env::args().any(|a| a == "--resanitize-comments") {
+        let changed = comment_store::open(config.comment_store).update_approved(&|c| {
+            let text = sanitize_comment_html(&c.text);
+            let changed = text != c.text;
+            c.text = text;
+            changed
+        });
+        println!("Sanitized {} comments", changed);
+        return Ok(());
+    }
+    // `blog --send-digest` emails new articles to newsletter subscribers and exits.
+    if std::env::args().any(|a| a == "--send-digest") {
+        return newsletter::send_digest(&config, &messages, &meta.recent_articles);
+    }
+
+    let meta: SharedMeta = Arc::new(RwLock::new(Arc::new(meta)));
+    if let Some(gemini_config) = &config.gemini {
+        gemini::serve(gemini_config, meta.clone(), gemini::Localization {
+            messages: messages.clone(),
+            locale: config.locale.clone(),
+            timezone,
+        });
+    }
     
     let log_file_draft_lock = Arc::new(Mutex::new(()));
-    let unchecked_comments_file_lock = Arc::new(Mutex::new(()));
+    let comments = comment_store::open(config.comment_store);
+    let mentions_file_lock = Arc::new(Mutex::new(()));
+    let content_file_lock = Arc::new(Mutex::new(()));
+    let followers_file_lock = Arc::new(Mutex::new(()));
+    let subscribers_file_lock = Arc::new(Mutex::new(()));
 
     let recent_comments = Arc::new(RwLock::new(TomlFile::read_default(&recent_comments_path())));
     let password = get_admin_password();
+    let cookie_key = get_cookie_key();
+    let tripcode_key = tripcode::key();
+    let micropub_token = get_micropub_token();
+    let activitypub = config.activitypub.as_ref().map(|c| Arc::new(activitypub::Actor::new(c, &config.base_url)));
+    if let Some(actor) = &activitypub {
+        let current = meta.read().unwrap().clone();
+        actix_web::rt::spawn(activitypub::deliver_new_articles(actor.clone(), current, followers_file_lock.clone()));
+    }
 
     actix_web::HttpServer::new(move || {
         actix_web::App::new()
             .data(AppState {
-                rng: Cell::new(init_rng()),
                 quote_data: quotes.clone(),
                 meta: meta.clone(),
                 log_file_draft_lock: log_file_draft_lock.clone(),
-                log_output: RefCell::new(log_file(log_file_draft_lock.clone())),
-                unchecked_comments_file_lock: unchecked_comments_file_lock.clone(),
+                log_output: RefCell::new(log_file(log_file_draft_lock.clone(), &timezone)),
+                comments: comments.clone(),
+                mentions_file_lock: mentions_file_lock.clone(),
+                content_file_lock: content_file_lock.clone(),
+                micropub_token: micropub_token.clone(),
+                activitypub: activitypub.clone(),
+                followers_file_lock: followers_file_lock.clone(),
+                subscribers_file_lock: subscribers_file_lock.clone(),
                 recent_comments: recent_comments.clone(),
                 admin_password: password.clone(),
+                cookie_key: cookie_key.clone(),
+                tripcode_key: tripcode_key.clone(),
+                config: config.clone(),
+                timezone,
+                messages: messages.clone(),
             })
             .wrap_fn(|req, srv| {
                 let data: &actix_web::web::Data<AppState> = req.app_data().unwrap();
-                let today = chrono::Utc::today();
+                let today = today(&data.timezone);
                 if today > data.log_output.borrow().1 {
-                    data.log_output.replace(log_file(data.log_file_draft_lock.clone()));
+                    data.log_output.replace(log_file(data.log_file_draft_lock.clone(), &data.timezone));
                 }
-                write!(data.log_output.borrow_mut().0, "{} {}\n", req.method(), req.path()).unwrap_or(());
+                writeln!(data.log_output.borrow_mut().0, "{} {}", req.method(), req.path()).unwrap_or(());
 
                 srv.call(req)
             })
+            .configure(api::configure)
+            .configure(activitypub::configure)
             .route("/", web::get().to(index))
             .route("/about", web::get().to(about))
+            .route("/quotes", web::get().to(quote_list))
+            .route("/quotes/{id}", web::get().to(quote))
             .route("/archive", web::get().to(archive))
+            .route("/a/{name}.md", web::get().to(article_markdown))
+            .route("/a/{name}/print", web::get().to(article_print))
+            .route("/a/{name}/comment/{index}", web::get().to(comment_permalink))
+            .route("/tripcode/{tripcode}", web::get().to(tripcode::page))
             .route("/a/{name}", web::get().to(article))
+            .route("/a/{name}", web::post().to(article_unlock))
+            .route("/tag/{name}.epub", web::get().to(tag_epub))
             .route("/tag/{name}", web::get().to(tag))
+            .route("/export/blog.epub", web::get().to(blog_epub))
+            .route("/lang/{lang}", web::get().to(lang_index))
+            .route("/lang/{lang}/feed.xml", web::get().to(lang_feed))
+            .route("/author/{slug}", web::get().to(author))
+            .route("/author/{slug}/feed.xml", web::get().to(author_feed))
+            .route("/comment/preview", web::post().to(comment_preview))
             .route("/comment/{name}", web::post().to(comment))
+            .route("/comment/{name}/edit/{id}", web::get().to(comment_edit::form))
+            .route("/comment/{name}/edit/{id}", web::post().to(comment_edit::save))
+            .route("/webmention", web::post().to(webmention::receive))
+            .route("/micropub", web::get().to(micropub::query))
+            .route("/micropub", web::post().to(micropub::publish))
+            .route("/newsletter/subscribe", web::post().to(newsletter::subscribe))
+            .route("/newsletter/confirm/{token}", web::get().to(newsletter::confirm))
+            .route("/newsletter/unsubscribe/{token}", web::get().to(newsletter::unsubscribe_page))
+            .route("/newsletter/unsubscribe/{token}", web::post().to(newsletter::unsubscribe))
             .route("/comment_approval", web::get().to(comment_approval))
             .route("/comment_approval", web::post().to(comment_approval_post))
+            .route("/comment_approval/a/{name}", web::get().to(comment_admin::list))
+            .route("/comment_approval/a/{name}", web::post().to(comment_admin::act))
+            .route("/comment_approval/a/{name}/{index}", web::get().to(comment_admin::edit_form))
             .route("/stats", web::get().to(stats))
             .service(actix_files::Files::new("/", "static"))
             .default_service(web::get().to(p404))
@@ -795,3 +2216,127 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_article_file_is_a_content_error() {
+        let meta_file: MetaFile = toml::from_str(r#"
+            tags = []
+            projects = []
+            [[articles]]
+            name = "no_such_article"
+            title = "Missing"
+            description = ""
+            date = 2020-10-02
+            tags = []
+        "#).unwrap();
+        let error = make_meta(meta_file).err().unwrap();
+        assert!(error.starts_with("Failed to open article no_such_article"), "{}", error);
+    }
+
+    #[test]
+    fn comment_approvals_escape_submitted_fields() {
+        let messages: HashMap<String, Messages> = read_toml("src/messages.toml");
+        let meta: Meta = serde_json::from_value(serde_json::json!({
+            "tags": [], "projects_map": {}, "articles_map": {}, "sources": {}, "recent_articles": [], "translations": {},
+            "articles_by_lang": {}, "authors": {}, "articles_by_author": {},
+        })).unwrap();
+        let quote = Quote { id: "q".to_string(), text: "Quote".to_string(), author: "Someone".to_string(), source: None, tags: Vec::new() };
+        let comment = UncheckedComment {
+            id: "1".to_string(),
+            article: "\"><img src=x onerror=alert(1)>".to_string(),
+            author: Some("<script>alert(2)</script>".to_string()),
+            text: "<script>alert(3)</script> <a href=\"javascript:alert(4)\">link</a>".to_string(),
+            edit_of: Some(0),
+            ..Default::default()
+        };
+        let html = CommentApprovalsTemplate {
+            layout: LayoutTemplate {
+                meta: Arc::new(meta),
+                locale: "en",
+                msg: &messages["en"],
+                timezone: chrono_tz::UTC,
+                quote: &quote,
+                recent_comments: Vec::new(),
+                recent_articles: Vec::new(),
+                newsletter: false,
+            },
+            comments: vec![(sanitize_comment_html(&comment_markdown_to_html(&comment.text)), &comment)],
+            mentions: Vec::new(),
+            published: Vec::new(),
+            author_name_fn,
+            html_escape_fn: |s| html_escape(s),
+            problems: Vec::new(),
+            csrf_token: "token",
+        }.render().unwrap();
+        let form = &html[html.find("<form").unwrap()..];
+        assert!(form.contains("&quot;&gt;&lt;img src=x onerror=alert(1)&gt;"), "{}", form);
+        assert!(form.contains("<a href=\"/a/&quot;&gt;&lt;img src=x onerror=alert(1)&gt;#0\">"), "{}", form);
+        for injected in ["<img", "<script", "<a href=\"javascript"] {
+            assert!(!form.contains(injected), "{} in {}", injected, form);
+        }
+    }
+
+    fn link(html: &str) -> (String, Vec<i64>) {
+        link_comment_references(html, "mindstorms", |n| n < 3)
+    }
+
+    #[test]
+    fn comment_references_become_links() {
+        let (html, references) = link("<p>&gt;&gt;0 and &gt;&gt;2, again &gt;&gt;0.</p>");
+        assert_eq!(html, concat!("<p><a class=\"comment_ref\" href=\"/a/mindstorms#0\" data-comment=\"0\">&gt;&gt;0</a> and ",
+            "<a class=\"comment_ref\" href=\"/a/mindstorms#2\" data-comment=\"2\">&gt;&gt;2</a>, again ",
+            "<a class=\"comment_ref\" href=\"/a/mindstorms#0\" data-comment=\"0\">&gt;&gt;0</a>.</p>"));
+        assert_eq!(references, vec![0, 2]);
+    }
+
+    #[test]
+    fn comment_references_to_missing_comments_stay_text() {
+        for html in ["<p>&gt;&gt;3 is later, &gt;&gt; alone, &gt;&gt;x</p>", "<p>&gt;&gt;99999999999999999999</p>"] {
+            assert_eq!(link(html), (html.to_string(), Vec::new()));
+        }
+    }
+
+    #[test]
+    fn comment_references_in_code_and_links_stay_text() {
+        let html = "<pre><code>a &gt;&gt;1</code></pre><p><code>&gt;&gt;1</code> <a href=\"x\">&gt;&gt;1</a> <b>&gt;&gt;1</b></p>";
+        let (linked, references) = link(html);
+        assert_eq!(linked, html.replace("<b>&gt;&gt;1</b>",
+            "<b><a class=\"comment_ref\" href=\"/a/mindstorms#1\" data-comment=\"1\">&gt;&gt;1</a></b>"));
+        assert_eq!(references, vec![1]);
+        // Stray closing tags don't turn linking off for the rest of the comment.
+        assert_eq!(link("</code>&gt;&gt;1").1, vec![1]);
+        assert_eq!(link("<p>x &lt; y &gt;&gt;1 ü").1, vec![1]);
+    }
+
+    fn thread_order(parents: &[Option<usize>], max_depth: usize) -> Vec<(usize, usize, usize)> {
+        CommentThread::new(parents, CommentThreadsConfig { max_depth, collapse_after: 5 }).order.into_iter()
+            .map(|e| (e.index, e.reply_count, e.closes))
+            .collect()
+    }
+
+    #[test]
+    fn comment_thread_nests_replies_under_their_parents() {
+        // 1 and 4 reply to 0, 2 replies to 1, 5 replies to a comment that isn't shown.
+        let parents = [None, Some(0), Some(1), None, Some(0), None];
+        assert_eq!(thread_order(&parents, 4), vec![(0, 3, 0), (1, 1, 0), (2, 0, 1), (4, 0, 1), (3, 0, 0), (5, 0, 0)]);
+    }
+
+    #[test]
+    fn comment_thread_flattens_below_max_depth() {
+        let parents = [None, Some(0), Some(1), Some(2), Some(1), Some(0)];
+        assert_eq!(thread_order(&parents, 4), vec![(0, 5, 0), (1, 3, 0), (2, 1, 0), (3, 0, 1), (4, 0, 1), (5, 0, 1)]);
+        // At depth 1 the replies to 1 are listed flat in posting order.
+        assert_eq!(thread_order(&parents, 2), vec![(0, 5, 0), (1, 0, 0), (2, 0, 0), (3, 0, 0), (4, 0, 0), (5, 0, 1)]);
+        assert_eq!(thread_order(&parents, 1), vec![(0, 0, 0), (1, 0, 0), (2, 0, 0), (3, 0, 0), (4, 0, 0), (5, 0, 0)]);
+    }
+
+    #[test]
+    fn comment_thread_shows_replies_to_later_comments_at_top_level() {
+        assert_eq!(thread_order(&[Some(1), None], 4), vec![(0, 0, 0), (1, 0, 0)]);
+        assert!(thread_order(&[], 4).is_empty());
+    }
+}
@@ -0,0 +1,384 @@
+use actix_web::web;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use super::{AppState, Article, Meta, resolve_tags, today};
+
+const META_PATH: &str = "src/meta.toml";
+/// The entries created through Micropub are kept apart from the hand written `src/meta.toml`, which is never
+/// rewritten. `make_meta` sees them in front of the other articles.
+const DRAFTS_PATH: &str = "src/micropub.toml";
+const MAX_GENERATED_TITLE: usize = 60;
+
+/// Micropub properties, every property is a list of values like in the JSON syntax.
+type Properties = HashMap<String, Vec<Value>>;
+
+enum Action {
+    Create(Properties),
+    Update { url: String, replace: Properties, add: Properties, delete: Value },
+    Delete { url: String },
+}
+
+fn error(status: actix_web::http::StatusCode, error: &str, description: &str) -> actix_web::HttpResponse {
+    actix_web::HttpResponse::build(status).json(json!({ "error": error, "error_description": description }))
+}
+
+fn invalid_request(description: &str) -> actix_web::HttpResponse {
+    error(actix_web::http::StatusCode::BAD_REQUEST, "invalid_request", description)
+}
+
+/// The content files couldn't be read or written, the request itself may be fine.
+fn server_error(description: String) -> actix_web::HttpResponse {
+    eprintln!("Micropub: {}", description);
+    error(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR, "server_error", &description)
+}
+
+/// Checks the bearer token from the Authorization header, or the `access_token` form field.
+fn check_token(req: &web::HttpRequest, data: &AppState, form_token: Option<&str>) -> Result<(), actix_web::HttpResponse> {
+    use actix_web::http::StatusCode;
+    let expected = match &data.micropub_token {
+        Some(token) => token,
+        None => return Err(error(StatusCode::FORBIDDEN, "forbidden", "Micropub is not enabled on this site")),
+    };
+    let header = req.headers().get("Authorization").and_then(|h| h.to_str().ok()).and_then(|h| h.strip_prefix("Bearer "));
+    match header.or(form_token) {
+        None => Err(error(StatusCode::UNAUTHORIZED, "unauthorized", "No access token was given")),
+        Some(token) if ring::constant_time::verify_slices_are_equal(token.trim().as_bytes(), expected.as_bytes()).is_ok() => Ok(()),
+        Some(_) => Err(error(StatusCode::FORBIDDEN, "forbidden", "The access token is not valid")),
+    }
+}
+
+/// Form values encode spaces as `+`.
+fn percent_decode(s: &str) -> String {
+    percent_encoding::percent_decode_str(&s.replace('+', " ")).decode_utf8_lossy().into_owned()
+}
+
+/// Parses an `application/x-www-form-urlencoded` body, keeping repeated keys.
+fn form_pairs(body: &str) -> Vec<(String, String)> {
+    body.split('&').filter(|p| !p.is_empty()).map(|pair| {
+        let mut kv = pair.splitn(2, '=');
+        (percent_decode(kv.next().unwrap_or_default()), percent_decode(kv.next().unwrap_or_default()))
+    }).collect()
+}
+
+fn form_action(pairs: Vec<(String, String)>) -> Result<Action, &'static str> {
+    let mut properties = Properties::new();
+    let mut h = None;
+    let mut action = None;
+    let mut url = None;
+    for (key, value) in pairs {
+        match key.as_str() {
+            "h" => h = Some(value),
+            "action" => action = Some(value),
+            "url" => url = Some(value),
+            "access_token" => (),
+            _ => properties.entry(key.trim_end_matches("[]").to_string()).or_default().push(Value::String(value)),
+        }
+    }
+    match (action.as_deref(), h.as_deref()) {
+        (Some("delete"), _) => Ok(Action::Delete { url: url.ok_or("Missing url")? }),
+        (Some(_), _) => Err("Only delete is supported with form encoding, use JSON for updates"),
+        (None, Some("entry")) => Ok(Action::Create(properties)),
+        (None, _) => Err("Only h=entry posts can be created"),
+    }
+}
+
+fn json_properties(value: Option<&Value>) -> Result<Properties, &'static str> {
+    match value {
+        None => Ok(Properties::new()),
+        Some(Value::Object(map)) => map.iter().map(|(k, v)| match v {
+            Value::Array(values) => Ok((k.clone(), values.clone())),
+            _ => Err("Property values must be arrays"),
+        }).collect(),
+        Some(_) => Err("Properties must be an object"),
+    }
+}
+
+fn json_action(body: &Value) -> Result<Action, &'static str> {
+    let url = || body["url"].as_str().map(str::to_string).ok_or("Missing url");
+    match body["action"].as_str() {
+        Some("delete") => Ok(Action::Delete { url: url()? }),
+        Some("update") => Ok(Action::Update {
+            url: url()?,
+            replace: json_properties(body.get("replace"))?,
+            add: json_properties(body.get("add"))?,
+            delete: body.get("delete").cloned().unwrap_or(Value::Null),
+        }),
+        Some(_) => Err("Unsupported action"),
+        None if body["type"][0] == "h-entry" => Ok(Action::Create(json_properties(body.get("properties"))?)),
+        None => Err("Only h-entry posts can be created"),
+    }
+}
+
+/// Turns a title into an article name: lowercase ascii letters and digits separated by underscores.
+fn slugify(s: &str) -> String {
+    let mut slug = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('_') {
+            slug.push('_');
+        }
+    }
+    slug.trim_end_matches('_').to_string()
+}
+
+fn article_name_from_url(data: &AppState, url: &str) -> Option<String> {
+    let name = url.strip_prefix(&data.config.base_url)?.strip_prefix("/a/")?;
+    Some(name.to_string()).filter(|n| !n.is_empty() && !n.contains('/'))
+}
+
+fn first_string(properties: &Properties, key: &str) -> Option<String> {
+    match properties.get(key)?.first()? {
+        Value::String(s) => Some(s.clone()),
+        // Content may also be given as {"html": ...} or {"text": ...}, both can go into the markdown file as is.
+        Value::Object(o) => o.get("html").or_else(|| o.get("text")).and_then(Value::as_str).map(str::to_string),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+struct Drafts {
+    #[serde(default)]
+    articles: Vec<Article>,
+}
+
+/// The article entries in `src/micropub.toml`, drafts until published by hand.
+pub fn drafts() -> Result<Vec<Article>, String> {
+    Ok(super::try_read_toml::<Drafts>(DRAFTS_PATH)?.articles)
+}
+
+fn read_file(path: &str) -> Result<toml::Value, actix_web::HttpResponse> {
+    super::try_read_toml(path).map_err(server_error)
+}
+
+fn write_file(path: &str, contents: String) -> Result<(), actix_web::HttpResponse> {
+    std::fs::write(path, contents).map_err(|e| server_error(format!("Failed to write {}: {}", path, e)))
+}
+
+fn articles_mut(file: &mut toml::Value) -> &mut Vec<toml::Value> {
+    file.as_table_mut().expect("src/micropub.toml is not a table")
+        .entry("articles").or_insert_with(|| toml::Value::Array(Vec::new()))
+        .as_array_mut().expect("articles in src/micropub.toml is not an array")
+}
+
+fn find_entry<'a>(file: &'a mut toml::Value, name: &str) -> Option<&'a mut toml::Value> {
+    articles_mut(file).iter_mut().find(|a| a.get("name").and_then(toml::Value::as_str) == Some(name))
+}
+
+fn write_drafts_file(drafts: &toml::Value) -> Result<(), actix_web::HttpResponse> {
+    let header = "# Articles created through Micropub, see src/micropub.rs. Set draft = false to publish one.\n\n";
+    let toml = toml::to_string(drafts).map_err(|e| server_error(format!("Failed to serialize {}: {}", DRAFTS_PATH, e)))?;
+    write_file(DRAFTS_PATH, format!("{}{}", header, toml))
+}
+
+/// Writes the properties into an article entry and returns the markdown content.
+fn apply_properties(entry: &mut toml::value::Table, properties: &Properties, meta: &Meta, tz: &chrono_tz::Tz) -> Result<String, String> {
+    let content = first_string(properties, "content").unwrap_or_default();
+    let title = match first_string(properties, "name") {
+        Some(name) => name,
+        None => {
+            let first_line = content.lines().find(|l| !l.trim().is_empty()).unwrap_or_default().trim();
+            let mut title: String = first_line.chars().take(MAX_GENERATED_TITLE).collect();
+            if first_line.chars().count() > MAX_GENERATED_TITLE {
+                title.push('…');
+            }
+            title
+        }
+    };
+    if title.is_empty() {
+        return Err("A post needs a name or content".to_string());
+    }
+    let mut tags: Vec<toml::Value> = Vec::new();
+    for category in properties.get("category").into_iter().flatten() {
+        let category = category.as_str().ok_or("Categories must be strings")?;
+        let (resolved, _) = resolve_tags(meta, category).ok_or_else(|| format!("Unknown category {}", category))?;
+        for tag in resolved {
+            if !tags.iter().any(|t| t.as_str() == Some(&tag.name)) {
+                tags.push(toml::Value::String(tag.name.clone()));
+            }
+        }
+    }
+    let date = match first_string(properties, "published") {
+        Some(published) => published.get(..10).and_then(|d| d.parse().ok()).ok_or("Invalid published date")?,
+        None => today(tz).format("%Y-%m-%d").to_string().parse().unwrap(),
+    };
+    entry.insert("description".to_string(), toml::Value::String(first_string(properties, "summary").unwrap_or_default()));
+    entry.insert("title".to_string(), toml::Value::String(title));
+    entry.insert("date".to_string(), toml::Value::Datetime(date));
+    entry.insert("tags".to_string(), toml::Value::Array(tags));
+    entry.insert("draft".to_string(), toml::Value::Boolean(true));
+    Ok(content)
+}
+
+/// The properties of an existing article, as returned by `q=source` and used as the base of updates.
+fn entry_properties(entry: &toml::Value, content: String) -> Properties {
+    let string = |key: &str| entry.get(key).and_then(toml::Value::as_str).unwrap_or_default().to_string();
+    let mut properties = Properties::new();
+    properties.insert("name".to_string(), vec![Value::String(string("title"))]);
+    properties.insert("summary".to_string(), vec![Value::String(string("description"))]);
+    properties.insert("content".to_string(), vec![Value::String(content)]);
+    properties.insert("published".to_string(), vec![Value::String(entry.get("date").map(|d| d.to_string()).unwrap_or_default())]);
+    properties.insert("category".to_string(), entry.get("tags").and_then(toml::Value::as_array).into_iter().flatten()
+        .filter_map(toml::Value::as_str).map(|t| Value::String(t.to_string())).collect());
+    let draft = entry.get("draft").and_then(toml::Value::as_bool).unwrap_or(false);
+    properties.insert("post-status".to_string(), vec![Value::String(if draft { "draft" } else { "published" }.to_string())]);
+    properties
+}
+
+fn article_path(name: &str) -> String {
+    format!("articles/{}.md", name)
+}
+
+fn create(data: &AppState, properties: Properties) -> Result<actix_web::HttpResponse, actix_web::HttpResponse> {
+    let meta = data.meta();
+    let _lock = data.content_file_lock.lock();
+    let mut drafts = read_file(DRAFTS_PATH)?;
+    let mut entry = toml::value::Table::new();
+    let content = apply_properties(&mut entry, &properties, &meta, &data.timezone).map_err(|e| invalid_request(&e))?;
+    let base = match first_string(&properties, "mp-slug") {
+        Some(slug) => slugify(&slug),
+        None => slugify(entry["title"].as_str().unwrap()),
+    };
+    let base = if base.is_empty() { "post".to_string() } else { base };
+    let mut name = base.clone();
+    let mut n = 1;
+    while find_entry(&mut drafts, &name).is_some() || meta.articles_map.contains_key(&name) || std::path::Path::new(&article_path(&name)).exists() {
+        n += 1;
+        name = format!("{}_{}", base, n);
+    }
+    // `name` goes first like in the hand written entries.
+    let mut named = toml::value::Table::new();
+    named.insert("name".to_string(), toml::Value::String(name.clone()));
+    named.extend(entry);
+    write_file(&article_path(&name), content)?;
+    articles_mut(&mut drafts).insert(0, toml::Value::Table(named));
+    write_drafts_file(&drafts)?;
+    data.reload_meta();
+    Ok(actix_web::HttpResponse::Created()
+        .header("Location", format!("{}/a/{}", data.config.base_url, name))
+        .finish())
+}
+
+/// Finds the draft a url points to. Published articles and the ones in `src/meta.toml` can't be changed through Micropub.
+fn draft_entry<'a>(data: &AppState, drafts: &'a mut toml::Value, url: &str) -> Result<(String, &'a mut toml::Value), actix_web::HttpResponse> {
+    let forbidden = || error(actix_web::http::StatusCode::FORBIDDEN, "forbidden", "Only drafts created through Micropub can be changed");
+    let name = article_name_from_url(data, url).ok_or_else(|| invalid_request("The url is not an article on this site"))?;
+    let entry = match find_entry(drafts, &name) {
+        Some(entry) => entry,
+        None if data.meta().articles_map.contains_key(&name) => return Err(forbidden()),
+        None => return Err(invalid_request("No such article")),
+    };
+    if !entry.get("draft").and_then(toml::Value::as_bool).unwrap_or(false) {
+        return Err(forbidden());
+    }
+    Ok((name, entry))
+}
+
+fn update(data: &AppState, url: &str, replace: Properties, add: Properties, delete: Value) -> Result<actix_web::HttpResponse, actix_web::HttpResponse> {
+    let meta = data.meta();
+    let _lock = data.content_file_lock.lock();
+    let mut drafts = read_file(DRAFTS_PATH)?;
+    let (name, entry) = draft_entry(data, &mut drafts, url)?;
+    let mut properties = entry_properties(entry, std::fs::read_to_string(article_path(&name)).unwrap_or_default());
+    properties.extend(replace);
+    for (key, values) in add {
+        properties.entry(key).or_default().extend(values);
+    }
+    match delete {
+        Value::Null => (),
+        Value::Array(keys) => for key in keys.iter().filter_map(Value::as_str) {
+            properties.remove(key);
+        },
+        Value::Object(values) => for (key, removed) in values {
+            let removed = removed.as_array().cloned().unwrap_or_default();
+            if let Some(current) = properties.get_mut(&key) {
+                current.retain(|v| !removed.contains(v));
+            }
+        },
+        _ => return Err(invalid_request("delete must be an array or an object")),
+    }
+    let content = apply_properties(entry.as_table_mut().unwrap(), &properties, &meta, &data.timezone).map_err(|e| invalid_request(&e))?;
+    write_file(&article_path(&name), content)?;
+    write_drafts_file(&drafts)?;
+    data.reload_meta();
+    Ok(actix_web::HttpResponse::NoContent().finish())
+}
+
+fn delete(data: &AppState, url: &str) -> Result<actix_web::HttpResponse, actix_web::HttpResponse> {
+    let _lock = data.content_file_lock.lock();
+    let mut drafts = read_file(DRAFTS_PATH)?;
+    let (name, _) = draft_entry(data, &mut drafts, url)?;
+    articles_mut(&mut drafts).retain(|a| a.get("name").and_then(toml::Value::as_str) != Some(&name));
+    write_drafts_file(&drafts)?;
+    std::fs::remove_file(article_path(&name)).unwrap_or(());
+    data.reload_meta();
+    Ok(actix_web::HttpResponse::NoContent().finish())
+}
+
+/// `POST /micropub`, creates, updates and deletes drafts. Everything created is a draft until published by hand.
+pub async fn publish(req: web::HttpRequest, body: web::Bytes, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let content_type = req.headers().get("Content-Type").and_then(|c| c.to_str().ok()).unwrap_or_default().to_ascii_lowercase();
+    let body = String::from_utf8_lossy(&body);
+    let action = if content_type.starts_with("application/json") {
+        if let Err(response) = check_token(&req, &data, None) {
+            return response;
+        }
+        match serde_json::from_str::<Value>(&body) {
+            Ok(json) => json_action(&json),
+            Err(_) => Err("The body is not valid JSON"),
+        }
+    } else if content_type.starts_with("application/x-www-form-urlencoded") {
+        let pairs = form_pairs(&body);
+        let token = pairs.iter().find(|(k, _)| k == "access_token").map(|(_, v)| v.as_str());
+        if let Err(response) = check_token(&req, &data, token) {
+            return response;
+        }
+        form_action(pairs)
+    } else {
+        return error(actix_web::http::StatusCode::UNSUPPORTED_MEDIA_TYPE, "invalid_request",
+            "Use application/x-www-form-urlencoded or application/json");
+    };
+    let result = match action {
+        Ok(Action::Create(properties)) => create(&data, properties),
+        Ok(Action::Update { url, replace, add, delete: removed }) => update(&data, &url, replace, add, removed),
+        Ok(Action::Delete { url }) => delete(&data, &url),
+        Err(e) => Err(invalid_request(e)),
+    };
+    result.unwrap_or_else(|response| response)
+}
+
+/// `GET /micropub?q=...`, the configuration and source queries.
+pub async fn query(req: web::HttpRequest, web::Query(query): web::Query<HashMap<String, String>>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    if let Err(response) = check_token(&req, &data, query.get("access_token").map(String::as_str)) {
+        return response;
+    }
+    match query.get("q").map(String::as_str) {
+        Some("config") => actix_web::HttpResponse::Ok().json(json!({ "syndicate-to": [], "q": ["config", "source", "syndicate-to"] })),
+        Some("syndicate-to") => actix_web::HttpResponse::Ok().json(json!({ "syndicate-to": [] })),
+        Some("source") => {
+            let url = query.get("url").map(String::as_str).unwrap_or_default();
+            let name = match article_name_from_url(&data, url) {
+                Some(name) => name,
+                None => return invalid_request("The url is not an article on this site"),
+            };
+            let _lock = data.content_file_lock.lock();
+            let mut drafts = match read_file(DRAFTS_PATH) {
+                Ok(drafts) => drafts,
+                Err(response) => return response,
+            };
+            let mut meta_file = match read_file(META_PATH) {
+                Ok(meta_file) => meta_file,
+                Err(response) => return response,
+            };
+            match find_entry(&mut drafts, &name).or_else(|| find_entry(&mut meta_file, &name)) {
+                Some(entry) => {
+                    let properties = entry_properties(entry, std::fs::read_to_string(article_path(&name)).unwrap_or_default());
+                    actix_web::HttpResponse::Ok().json(json!({ "type": ["h-entry"], "properties": properties }))
+                },
+                None => invalid_request("No such article"),
+            }
+        },
+        _ => invalid_request("Unsupported query"),
+    }
+}
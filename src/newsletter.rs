@@ -0,0 +1,438 @@
+use actix_web::web;
+use askama::Template;
+use serde_derive::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read, Write};
+use super::{AppState, Article, LayoutTemplate, Messages, layout_template, newsletter_sent_path, newsletter_subscribers_path,
+    read_toml_default, request_locale};
+
+/// Unconfirmed subscriptions are forgotten after this many seconds.
+const CONFIRMATION_TIME: u64 = 7 * 24 * 60 * 60;
+/// Subscribing again sends the confirmation link again, at most once in this many seconds per address.
+const CONFIRMATION_RESEND_TIME: u64 = 60 * 60;
+const MAX_EMAIL_LENGTH: usize = 254;
+const SMTP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[derive(Clone, Deserialize)]
+pub struct NewsletterConfig {
+    /// The From header, e.g. `Lesser Scholar <newsletter@example.com>`.
+    from: String,
+    smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    smtp_port: u16,
+    /// Upgrade the connection with STARTTLS before authenticating.
+    #[serde(default)]
+    starttls: bool,
+    username: Option<String>,
+    /// File containing the SMTP password, kept out of the config like the other secrets.
+    password_file: Option<String>,
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Subscriber {
+    pub email: String,
+    /// Secret in the confirmation and unsubscribe links.
+    pub token: String,
+    pub confirmed: bool,
+    pub subscribed: u64,
+    /// Locale of the emails, the one the subscribe form was shown in.
+    pub locale: String,
+    /// When the confirmation link was last sent.
+    #[serde(default)]
+    pub last_sent: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Subscribers {
+    #[serde(default)]
+    pub subscribers: Vec<Subscriber>,
+}
+
+/// Articles that have already been in a digest.
+#[derive(Default, Serialize, Deserialize)]
+struct Sent {
+    #[serde(default)]
+    articles: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "newsletter.html")]
+struct NewsletterTemplate<'a> {
+    layout: LayoutTemplate<'a>,
+    message: &'a str,
+    /// Shows the unsubscribe button for this token.
+    unsubscribe: Option<&'a str>,
+}
+
+#[derive(Template)]
+#[template(path = "email/confirm.txt")]
+struct ConfirmEmailTemplate<'a> {
+    msg: &'a Messages,
+    link: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email/digest.txt")]
+struct DigestEmailTemplate<'a> {
+    msg: &'a Messages,
+    base_url: &'a str,
+    articles: &'a [&'a Article],
+    unsubscribe: &'a str,
+}
+
+#[derive(Deserialize)]
+pub struct SubscribeForm {
+    email: String,
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// A deliberately loose check, the confirmation email is the real test. Anything that could break out of
+/// an SMTP command or a header is rejected.
+fn valid_email(email: &str) -> bool {
+    let mut parts = email.split('@');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(local), Some(domain), None) => !local.is_empty() && domain.contains('.') && !domain.starts_with('.')
+            && !domain.ends_with('.') && email.len() <= MAX_EMAIL_LENGTH
+            && email.chars().all(|c| c.is_ascii_graphic() && !"<>()[],;:\\\"".contains(c)),
+        _ => false,
+    }
+}
+
+fn new_token() -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    (0..32).map(|_| std::char::from_digit(rng.gen_range(0, 16), 16).unwrap()).collect()
+}
+
+fn write_subscribers(subscribers: &Subscribers) {
+    let path = newsletter_subscribers_path();
+    std::fs::create_dir_all(std::path::Path::new(&path).parent().unwrap()).unwrap();
+    std::fs::write(path, toml::to_string(subscribers).unwrap()).unwrap();
+}
+
+/// `=?UTF-8?B?...?=` for header values that aren't plain ascii.
+fn encode_header(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        value.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", base64::encode(value))
+    }
+}
+
+struct Connection<S: Read + Write> {
+    stream: BufReader<S>,
+}
+
+impl<S: Read + Write> Connection<S> {
+    /// Reads a possibly multiline reply, failing unless its code is `expect`.
+    fn reply(&mut self, expect: u16) -> std::io::Result<()> {
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "SMTP server closed the connection"));
+            }
+            if line.len() < 4 || !line.is_char_boundary(3) {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Bad SMTP reply {}", line.trim_end())));
+            }
+            if line.as_bytes()[3] == b'-' {
+                continue;
+            }
+            return match line[..3].parse::<u16>() {
+                Ok(code) if code == expect => Ok(()),
+                _ => Err(std::io::Error::other(format!("SMTP server replied {}", line.trim_end()))),
+            };
+        }
+    }
+
+    fn command(&mut self, command: &str, expect: u16) -> std::io::Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        self.reply(expect)
+    }
+
+    /// The greeting and EHLO of a new connection.
+    fn hello(&mut self, domain: &str) -> std::io::Result<()> {
+        self.reply(220)?;
+        self.command(&format!("EHLO {}", domain), 250)
+    }
+
+    /// Everything after the greeting and the possible STARTTLS.
+    fn send(&mut self, config: &NewsletterConfig, to: &str, message: &str) -> std::io::Result<()> {
+        if let (Some(username), Some(password_file)) = (&config.username, &config.password_file) {
+            let password = std::fs::read_to_string(password_file)?;
+            let credentials = base64::encode(format!("\0{}\0{}", username, password.trim()));
+            self.command(&format!("AUTH PLAIN {}", credentials), 235)?;
+        }
+        let from = config.from.rsplit('<').next().unwrap_or_default().trim_end_matches('>');
+        self.command(&format!("MAIL FROM:<{}>", from), 250)?;
+        self.command(&format!("RCPT TO:<{}>", to), 250)?;
+        self.command("DATA", 354)?;
+        // The body is base64 so no line can start with a dot and nothing needs stuffing.
+        self.command(&format!("{}\r\n.", message), 250)?;
+        self.command("QUIT", 221)
+    }
+}
+
+fn tls_config() -> std::sync::Arc<rustls::ClientConfig> {
+    let mut config = rustls::ClientConfig::new();
+    config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    std::sync::Arc::new(config)
+}
+
+/// Sends a plain text email. `unsubscribe` is the link for the `List-Unsubscribe` headers.
+fn send_mail(config: &NewsletterConfig, to: &str, subject: &str, body: &str, unsubscribe: Option<&str>) -> std::io::Result<()> {
+    let domain = config.from.rsplit('@').next().unwrap_or("localhost").trim_end_matches('>');
+    let mut message = format!("From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\nMIME-Version: 1.0\r\n",
+        encode_header(&config.from), to, encode_header(subject), chrono::Utc::now().to_rfc2822(), new_token(), domain);
+    if let Some(link) = unsubscribe {
+        message.push_str(&format!("List-Unsubscribe: <{}>\r\nList-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n", link));
+    }
+    message.push_str("Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n");
+    let encoded = base64::encode(body.replace('\n', "\r\n"));
+    let lines: Vec<&str> = encoded.as_bytes().chunks(76).map(|l| std::str::from_utf8(l).unwrap()).collect();
+    message.push_str(&lines.join("\r\n"));
+
+    let tcp = std::net::TcpStream::connect((config.smtp_host.as_str(), config.smtp_port))?;
+    tcp.set_read_timeout(Some(SMTP_TIMEOUT))?;
+    tcp.set_write_timeout(Some(SMTP_TIMEOUT))?;
+    let mut plain = Connection { stream: BufReader::new(tcp) };
+    plain.hello(domain)?;
+    if !config.starttls {
+        return plain.send(config, to, &message);
+    }
+    plain.command("STARTTLS", 220)?;
+    let name = webpki::DNSNameRef::try_from_ascii_str(&config.smtp_host)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "SMTP host is not a valid DNS name"))?;
+    let session = rustls::ClientSession::new(&tls_config(), name);
+    let mut tls = Connection { stream: BufReader::new(rustls::StreamOwned::new(session, plain.stream.into_inner())) };
+    tls.command(&format!("EHLO {}", domain), 250)?;
+    tls.send(config, to, &message)
+}
+
+fn page(req: &web::HttpRequest, data: &web::Data<AppState>, message: &str, unsubscribe: Option<&str>) -> actix_web::HttpResponse {
+    let tmpl = NewsletterTemplate {
+        layout: layout_template(req, data),
+        message,
+        unsubscribe,
+    };
+    actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
+}
+
+/// Starts a subscription by sending the confirmation link. Known addresses get the same answer as new ones,
+/// also when the link was sent too recently to send it again.
+pub async fn subscribe(req: web::HttpRequest, web::Form(form): web::Form<SubscribeForm>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let config = match &data.config.newsletter {
+        Some(config) => config.clone(),
+        None => return actix_web::HttpResponse::NotFound().finish(),
+    };
+    let msg = &data.messages[request_locale(&req, &data)];
+    let email = form.email.trim().to_string();
+    if !valid_email(&email) {
+        return page(&req, &data, &msg.newsletter_invalid_email, None);
+    }
+    let token = {
+        let _lock = data.subscribers_file_lock.lock().unwrap();
+        let mut subscribers: Subscribers = read_toml_default(&newsletter_subscribers_path());
+        let now = now();
+        subscribers.subscribers.retain(|s| s.confirmed || s.subscribed + CONFIRMATION_TIME > now);
+        match subscribers.subscribers.iter_mut().find(|s| s.email.eq_ignore_ascii_case(&email)) {
+            Some(s) if s.confirmed || s.last_sent + CONFIRMATION_RESEND_TIME > now => None,
+            Some(s) => {
+                s.last_sent = now;
+                Some(s.token.clone())
+            },
+            None => {
+                let token = new_token();
+                subscribers.subscribers.push(Subscriber {
+                    email: email.clone(),
+                    token: token.clone(),
+                    confirmed: false,
+                    subscribed: now,
+                    locale: request_locale(&req, &data).to_string(),
+                    last_sent: now,
+                });
+                Some(token)
+            }
+        }.inspect(|_| write_subscribers(&subscribers))
+    };
+    if let Some(token) = token {
+        let link = format!("{}/newsletter/confirm/{}", data.config.base_url, token);
+        let body = ConfirmEmailTemplate { msg, link: &link }.render().unwrap();
+        let subject = msg.newsletter_confirm_subject.clone();
+        if let Err(e) = web::block(move || send_mail(&config, &email, &subject, &body, None)).await {
+            println!("Failed to send newsletter confirmation: {}", e);
+            return super::error(&req, &e.to_string(), &data).await;
+        }
+    }
+    page(&req, &data, &msg.newsletter_check_email, None)
+}
+
+pub async fn confirm(req: web::HttpRequest, web::Path(token): web::Path<String>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let msg = &data.messages[request_locale(&req, &data)];
+    let _lock = data.subscribers_file_lock.lock().unwrap();
+    let mut subscribers: Subscribers = read_toml_default(&newsletter_subscribers_path());
+    let now = now();
+    match subscribers.subscribers.iter_mut().find(|s| s.token == token && (s.confirmed || s.subscribed + CONFIRMATION_TIME > now)) {
+        Some(s) => {
+            s.confirmed = true;
+            write_subscribers(&subscribers);
+            page(&req, &data, &msg.newsletter_confirmed, None)
+        },
+        None => page(&req, &data, &msg.newsletter_invalid_link, None),
+    }
+}
+
+/// Asks before unsubscribing, so that mail scanners following links don't unsubscribe anyone.
+pub async fn unsubscribe_page(req: web::HttpRequest, web::Path(token): web::Path<String>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let msg = &data.messages[request_locale(&req, &data)];
+    let subscribers: Subscribers = read_toml_default(&newsletter_subscribers_path());
+    match subscribers.subscribers.iter().any(|s| s.token == token) {
+        true => page(&req, &data, &msg.newsletter_unsubscribe_question, Some(&token)),
+        false => page(&req, &data, &msg.newsletter_invalid_link, None),
+    }
+}
+
+/// Also the target of one-click `List-Unsubscribe-Post` from mail clients.
+pub async fn unsubscribe(req: web::HttpRequest, web::Path(token): web::Path<String>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let msg = &data.messages[request_locale(&req, &data)];
+    let _lock = data.subscribers_file_lock.lock().unwrap();
+    let mut subscribers: Subscribers = read_toml_default(&newsletter_subscribers_path());
+    let count = subscribers.subscribers.len();
+    subscribers.subscribers.retain(|s| s.token != token);
+    if subscribers.subscribers.len() == count {
+        return page(&req, &data, &msg.newsletter_invalid_link, None);
+    }
+    write_subscribers(&subscribers);
+    page(&req, &data, &msg.newsletter_unsubscribed, None)
+}
+
+/// `blog --send-digest`: emails the public articles that haven't been in a digest yet to every confirmed
+/// subscriber. The first run only records the existing articles. Meant to be run from cron.
+pub fn send_digest(config: &super::Config, messages: &std::collections::HashMap<String, Messages>, articles: &[Article]) -> std::io::Result<()> {
+    let newsletter = config.newsletter.as_ref().expect("No [newsletter] in src/config.toml");
+    let path = newsletter_sent_path();
+    let first_run = !std::path::Path::new(&path).exists();
+    let mut sent: Sent = read_toml_default(&path);
+    let new: Vec<&Article> = articles.iter().filter(|a| !sent.articles.contains(&a.name)).collect();
+    if new.is_empty() {
+        println!("No new articles");
+        return Ok(());
+    }
+    if !first_run {
+        let subscribers: Subscribers = read_toml_default(&newsletter_subscribers_path());
+        let confirmed: Vec<&Subscriber> = subscribers.subscribers.iter().filter(|s| s.confirmed).collect();
+        let mut failures = 0;
+        for s in &confirmed {
+            let msg = messages.get(&s.locale).unwrap_or(&messages[&config.locale]);
+            let unsubscribe = format!("{}/newsletter/unsubscribe/{}", config.base_url, s.token);
+            let body = DigestEmailTemplate { msg, base_url: &config.base_url, articles: &new, unsubscribe: &unsubscribe }.render().unwrap();
+            if let Err(e) = send_mail(newsletter, &s.email, &msg.newsletter_digest_subject, &body, Some(&unsubscribe)) {
+                println!("Failed to send digest to {}: {}", s.email, e);
+                failures += 1;
+            }
+        }
+        // When nothing got through, e.g. the SMTP server is down, the next run tries again.
+        if !confirmed.is_empty() && failures == confirmed.len() {
+            return Err(std::io::Error::other("No digest could be sent"));
+        }
+        println!("Sent {} new article(s) to {} subscriber(s)", new.len(), confirmed.len() - failures);
+    }
+    sent.articles.extend(new.iter().map(|a| a.name.clone()));
+    std::fs::create_dir_all(std::path::Path::new(&path).parent().unwrap())?;
+    std::fs::write(&path, toml::to_string(&sent).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays the server side of an SMTP session from canned replies and records what the client sent.
+    struct StandIn {
+        replies: std::io::Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl Read for StandIn {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for StandIn {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.sent.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn connection(replies: &str) -> Connection<StandIn> {
+        Connection { stream: BufReader::new(StandIn { replies: std::io::Cursor::new(replies.as_bytes().to_vec()), sent: Vec::new() }) }
+    }
+
+    fn sent(connection: Connection<StandIn>) -> String {
+        String::from_utf8(connection.stream.into_inner().sent).unwrap()
+    }
+
+    fn config(password_file: Option<String>) -> NewsletterConfig {
+        NewsletterConfig {
+            from: "Lesser Scholar <newsletter@blog.example>".to_string(),
+            smtp_host: "smtp.example".to_string(),
+            smtp_port: 25,
+            starttls: false,
+            username: password_file.as_ref().map(|_| "blog".to_string()),
+            password_file,
+        }
+    }
+
+    #[test]
+    fn sends_message_with_authentication() {
+        let password_file = std::env::temp_dir().join(format!("newsletter-test-{}.txt", new_token()));
+        std::fs::write(&password_file, "secret\n").unwrap();
+        let mut smtp = connection(concat!(
+            "220 smtp.example ESMTP\r\n",
+            "250-smtp.example\r\n250-AUTH PLAIN\r\n250 8BITMIME\r\n",
+            "235 2.7.0 Authentication successful\r\n",
+            "250 OK\r\n250 OK\r\n354 Go ahead\r\n250 Queued\r\n221 Bye\r\n"));
+        smtp.hello("blog.example").unwrap();
+        let result = smtp.send(&config(Some(password_file.to_string_lossy().to_string())), "reader@example.com", "Subject: Hi\r\n\r\nSGk=");
+        std::fs::remove_file(&password_file).unwrap();
+        result.unwrap();
+        assert_eq!(sent(smtp), concat!(
+            "EHLO blog.example\r\n",
+            "AUTH PLAIN AGJsb2cAc2VjcmV0\r\n",
+            "MAIL FROM:<newsletter@blog.example>\r\n",
+            "RCPT TO:<reader@example.com>\r\n",
+            "DATA\r\n",
+            "Subject: Hi\r\n\r\nSGk=\r\n.\r\n",
+            "QUIT\r\n"));
+    }
+
+    #[test]
+    fn stops_at_unexpected_reply() {
+        let mut smtp = connection("220 smtp.example ESMTP\r\n250 smtp.example\r\n250 OK\r\n550-No such user\r\n550 here\r\n");
+        smtp.hello("blog.example").unwrap();
+        let error = smtp.send(&config(None), "nobody@example.com", "Subject: Hi").unwrap_err();
+        assert_eq!(error.to_string(), "SMTP server replied 550 here");
+        let sent = sent(smtp);
+        assert!(sent.ends_with("RCPT TO:<nobody@example.com>\r\n"), "{}", sent);
+        assert!(!sent.contains("DATA"));
+    }
+
+    #[test]
+    fn rejects_bad_and_missing_replies() {
+        assert!(connection("554 No service\r\n").hello("blog.example").is_err());
+        assert_eq!(connection("22\n").reply(220).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(connection("250-smtp.example\r\n").reply(250).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
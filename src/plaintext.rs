@@ -0,0 +1,299 @@
+use super::{Article, ApprovedComment, Messages, Meta, Tag, format_article_date, timestamp_to_datestring};
+use super::gemini::extract_links;
+
+const WIDTH: usize = 80;
+
+pub struct Context<'a> {
+    pub meta: &'a Meta,
+    pub msg: &'a Messages,
+    pub timezone: &'a chrono_tz::Tz,
+    pub base_url: &'a str,
+}
+
+/// A page being written out, with links collected as numbered footnotes.
+struct Page<'a> {
+    ctx: &'a Context<'a>,
+    out: String,
+    footnotes: Vec<String>,
+}
+
+/// Greedy word wrap, `first` prefixes the first line and `rest` the others.
+fn wrap(text: &str, first: &str, rest: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 16);
+    let mut line = String::from(first);
+    let mut empty = true;
+    for word in text.split_whitespace() {
+        if !empty && line.chars().count() + 1 + word.chars().count() > WIDTH {
+            out.push_str(line.trim_end());
+            out.push('\n');
+            line = String::from(rest);
+            empty = true;
+        }
+        if !empty {
+            line.push(' ');
+        }
+        line.push_str(word);
+        empty = false;
+    }
+    out.push_str(line.trim_end());
+    out.push('\n');
+    out
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+impl<'a> Page<'a> {
+    fn new(ctx: &'a Context<'a>) -> Page<'a> {
+        Page { ctx, out: String::new(), footnotes: Vec::new() }
+    }
+
+    /// Returns `label[n]`, adding `url` as footnote n. Site-relative urls are made absolute.
+    fn link(&mut self, label: &str, url: &str) -> String {
+        let url = if url.starts_with('/') { format!("{}{}", self.ctx.base_url, url) } else { url.to_string() };
+        let n = match self.footnotes.iter().position(|f| *f == url) {
+            Some(i) => i + 1,
+            None => {
+                self.footnotes.push(url);
+                self.footnotes.len()
+            }
+        };
+        format!("{}[{}]", label, n)
+    }
+
+    fn heading(&mut self, text: &str, underline: char) {
+        self.out.push_str(text);
+        self.out.push('\n');
+        self.out.push_str(&underline.to_string().repeat(text.chars().count().min(WIDTH)));
+        self.out.push_str("\n\n");
+    }
+
+    fn paragraph(&mut self, text: &str, first: &str, rest: &str) {
+        self.out.push_str(&wrap(text, first, rest));
+    }
+
+    /// Like `extract_links`, but keeps a footnote marker where each link was.
+    fn inline(&mut self, line: &str) -> String {
+        let (_, links) = extract_links(line);
+        let mut text = String::with_capacity(line.len());
+        let mut rest = line;
+        for (url, label) in links {
+            let start = rest.find(&format!("[{}]({})", label, url)).unwrap();
+            text.push_str(rest[..start].trim_end_matches('!'));
+            text.push_str(&self.link(&label, &url));
+            rest = &rest[start + label.len() + url.len() + 4..];
+        }
+        text.push_str(rest);
+        text
+    }
+
+    fn markdown(&mut self, md: &str) {
+        let mut paragraph: Vec<String> = Vec::new();
+        let mut preformatted = false;
+        for line in md.lines() {
+            let trimmed = line.trim();
+            if trimmed.starts_with("```") {
+                self.flush(&mut paragraph);
+                preformatted = !preformatted;
+                continue;
+            }
+            if preformatted {
+                self.out.push_str("    ");
+                self.out.push_str(line);
+                self.out.push('\n');
+                continue;
+            }
+            if trimmed.is_empty() {
+                self.flush(&mut paragraph);
+                if !self.out.ends_with("\n\n") {
+                    self.out.push('\n');
+                }
+            } else if trimmed.starts_with('#') {
+                self.flush(&mut paragraph);
+                let level = trimmed.chars().take_while(|&c| c == '#').count();
+                let text = self.inline(trimmed[level..].trim());
+                self.heading(&text, if level == 1 { '=' } else { '-' });
+            } else if trimmed.starts_with("* ") || trimmed.starts_with("- ") || trimmed.starts_with("+ ") {
+                self.flush(&mut paragraph);
+                let text = self.inline(&trimmed[2..]);
+                self.paragraph(&text, "  * ", "    ");
+            } else if let Some(quote) = trimmed.strip_prefix('>') {
+                self.flush(&mut paragraph);
+                let text = self.inline(quote.trim());
+                self.paragraph(&text, "  | ", "  | ");
+            } else {
+                let text = self.inline(trimmed);
+                paragraph.push(text);
+            }
+        }
+        self.flush(&mut paragraph);
+    }
+
+    fn flush(&mut self, paragraph: &mut Vec<String>) {
+        if !paragraph.is_empty() {
+            let text = paragraph.join(" ");
+            self.paragraph(&text, "", "");
+            paragraph.clear();
+        }
+    }
+
+    /// Renders the markdown renderer's HTML output (comments, tag descriptions) as wrapped text.
+    fn html(&mut self, html: &str, indent: &str) {
+        let mut text = String::with_capacity(html.len());
+        let mut href: Option<String> = None;
+        let mut label_start = 0;
+        let mut rest = html;
+        while let Some(i) = rest.find('<') {
+            text.push_str(&rest[..i]);
+            let end = rest[i..].find('>').map_or(rest.len(), |e| i + e);
+            let tag = &rest[i + 1..end];
+            let name: String = tag.trim_start_matches('/').chars().take_while(|c| c.is_ascii_alphanumeric()).collect();
+            match (tag.starts_with('/'), name.to_lowercase().as_str()) {
+                (false, "a") => {
+                    href = tag.find("href=").and_then(|h| {
+                        let value = &tag[h + 5..];
+                        let quote = value.chars().next().filter(|&q| q == '\'' || q == '"')?;
+                        value[1..].split(quote).next().map(str::to_string)
+                    });
+                    label_start = text.len();
+                },
+                (true, "a") => if let Some(url) = href.take() {
+                    let label = decode_entities(&text[label_start..]);
+                    text.truncate(label_start);
+                    text.push_str(&self.link(&label, &decode_entities(&url)));
+                },
+                (_, "p") | (_, "pre") | (_, "ul") | (_, "ol") | (_, "blockquote") => text.push_str("\n\n"),
+                (_, "br") | (false, "li") => text.push('\n'),
+                _ => (),
+            }
+            rest = rest.get(end + 1..).unwrap_or_default();
+        }
+        text.push_str(rest);
+        for block in decode_entities(&text).split("\n\n").filter(|b| !b.trim().is_empty()) {
+            for line in block.lines().filter(|l| !l.trim().is_empty()) {
+                self.paragraph(line, indent, indent);
+            }
+            self.out.push('\n');
+        }
+    }
+
+    fn date(&self, article: &Article) -> String {
+        format_article_date(&article.date, self.ctx.timezone, &self.ctx.msg.date_format)
+    }
+
+    fn article_list(&mut self, articles: &[&Article]) {
+        if articles.is_empty() {
+            let text = self.ctx.msg.nothing_here_yet.clone();
+            self.paragraph(&text, "", "");
+        }
+        for a in articles {
+            let title = self.link(&a.title, &format!("/a/{}", a.name));
+            self.out.push_str(&title);
+            self.out.push('\n');
+            let text = format!("{}. {}", self.date(a), a.description);
+            self.paragraph(&text, "    ", "    ");
+            self.out.push('\n');
+        }
+    }
+
+    fn finish(mut self) -> String {
+        if !self.footnotes.is_empty() {
+            if !self.out.ends_with("\n\n") {
+                self.out.push('\n');
+            }
+            for (i, url) in self.footnotes.iter().enumerate() {
+                self.out.push_str(&format!("[{}] {}\n", i + 1, url));
+            }
+        }
+        self.out
+    }
+}
+
+pub fn index(ctx: &Context, articles: &[Article]) -> String {
+    let mut page = Page::new(ctx);
+    page.heading("Lesser Scholar", '=');
+    page.heading(&ctx.msg.latest_articles, '-');
+    page.article_list(&articles.iter().collect::<Vec<_>>());
+    page.heading(&ctx.msg.tags, '-');
+    let tags: Vec<String> = ctx.meta.tags.iter()
+        .map(|t| page.link(&format!("{} ({})", t.name, t.count), &format!("/tag/{}", t.name)))
+        .collect();
+    page.paragraph(&tags.join(", "), "", "");
+    page.finish()
+}
+
+pub fn tag(ctx: &Context, tags: &[&Tag], articles: &[&Article]) -> String {
+    let mut page = Page::new(ctx);
+    let names: Vec<&str> = tags.iter().map(|t| t.name.as_str()).collect();
+    page.heading(&format!("{} {}", ctx.msg.posts_in, names.join(" + ")), '=');
+    if let [tag] = tags {
+        page.html(&tag.description, "");
+        if !tag.children.is_empty() {
+            let children: Vec<String> = tag.children.iter().map(|c| page.link(c, &format!("/tag/{}", c))).collect();
+            page.paragraph(&format!("{} {}", ctx.msg.subtags, children.join(", ")), "", "");
+            page.out.push('\n');
+        }
+    }
+    page.article_list(articles);
+    page.finish()
+}
+
+pub fn article(ctx: &Context, article: &Article, comments: &[ApprovedComment]) -> String {
+    let mut page = Page::new(ctx);
+    page.heading(&article.title, '=');
+    let mut header = format!("{} {}", ctx.msg.posted_on, page.date(article));
+    if !article.author.is_empty() {
+        let names: Vec<String> = article.author.iter()
+            .map(|slug| page.link(&ctx.meta.authors[slug].name, &format!("/author/{}", slug)))
+            .collect();
+        header.push_str(&format!(" {} {}", ctx.msg.by, names.join(", ")));
+    }
+    let tags: Vec<String> = article.tags.iter().map(|t| page.link(t, &format!("/tag/{}", t))).collect();
+    header.push_str(&format!(". {} {}", ctx.msg.tagged, tags.join(", ")));
+    page.paragraph(&header, "", "");
+    page.out.push('\n');
+    page.markdown(&ctx.meta.sources[&article.name]);
+    if !comments.is_empty() {
+        if !page.out.ends_with("\n\n") {
+            page.out.push('\n');
+        }
+        page.heading(&ctx.msg.comments, '-');
+        for c in comments {
+            if c.deleted {
+                page.paragraph(&format!("#{}: {}", c.post_index, ctx.msg.comment_deleted), "", "    ");
+                continue;
+            }
+            let mut author = c.author.clone().unwrap_or_else(|| ctx.msg.anon.clone());
+            if let Some(tripcode) = &c.tripcode {
+                author.push_str(&format!(" !{}", tripcode));
+            }
+            let author = match &c.website {
+                Some(website) => page.link(&author, website),
+                None => author,
+            };
+            let mut line = format!("#{} {} {}, {}", c.post_index, ctx.msg.comment_on,
+                timestamp_to_datestring(&c.timestamp, ctx.timezone, &ctx.msg.date_format), author);
+            match c.reply_to {
+                Some(parent) => line.push_str(&format!(" {} #{}", ctx.msg.replied_to, parent)),
+                None => line.push_str(&format!(" {}", ctx.msg.wrote)),
+            }
+            page.paragraph(&line, "", "    ");
+            page.html(&c.text, "    ");
+        }
+    }
+    page.finish()
+}
+
+/// What a locked private article shows instead of its content.
+pub fn locked(ctx: &Context, article: &Article) -> String {
+    let mut page = Page::new(ctx);
+    page.heading(&article.title, '=');
+    page.paragraph(&ctx.msg.private_article_note, "", "");
+    page.finish()
+}
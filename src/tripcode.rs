@@ -0,0 +1,119 @@
+//! Imageboard style tripcodes: `name#secret` in the comment author field is published as `name`
+//! followed by `!tripcode`, derived from the secret with a site key, so a commenter can show that
+//! their comments come from the same person without an account.
+use actix_web::web;
+use askama::Template;
+use super::{AppState, Article, LayoutTemplate, layout_template, p404, request_messages, timestamp_to_datestring};
+
+const KEY_FILE: &str = "tripcode_secret.txt";
+
+/// The site key from `tripcode_secret.txt`. It is created on the first start, changing it changes every tripcode.
+pub fn key() -> ring::hmac::Key {
+    let secret = match std::fs::read_to_string(KEY_FILE) {
+        Ok(secret) => secret.trim().to_string(),
+        Err(_) => {
+            let secret = format!("{:032x}", rand::random::<u128>());
+            std::fs::write(KEY_FILE, &secret).expect("Failed to write tripcode_secret.txt");
+            secret
+        }
+    };
+    ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes())
+}
+
+/// Splits `name#secret` into the name and the tripcode of the secret, other names have no tripcode.
+pub fn split(author: &str, key: &ring::hmac::Key) -> (String, Option<String>) {
+    match author.split_once('#') {
+        Some((name, secret)) if !secret.is_empty() => {
+            let tag = ring::hmac::sign(key, secret.as_bytes());
+            (name.trim().to_string(), Some(base64::encode_config(&tag.as_ref()[..9], base64::URL_SAFE_NO_PAD)))
+        },
+        _ => (author.to_string(), None),
+    }
+}
+
+struct TripcodeComment<'a> {
+    article: &'a Article,
+    post_index: i64,
+    timestamp: u64,
+    author: String,
+    date: String,
+    datetime: String,
+    text: String,
+}
+
+#[derive(Template)]
+#[template(path = "tripcode.html", escape = "none")]
+struct TripcodeTemplate<'a> {
+    layout: LayoutTemplate<'a>,
+    tripcode: &'a str,
+    comments: Vec<TripcodeComment<'a>>,
+}
+
+/// `/tripcode/{tripcode}`, the published comments on public articles signed with a tripcode, newest first.
+pub async fn page(req: web::HttpRequest, web::Path(tripcode): web::Path<String>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let meta = data.meta();
+    let msg = request_messages(&req, &data);
+    let mut comments: Vec<TripcodeComment> = data.comments.by_tripcode(&tripcode).into_iter()
+        .filter_map(|(article, c)| {
+            let (a, _) = meta.articles_map.get(&article).filter(|(a, _)| a.is_public() && !a.draft)?;
+            Some(TripcodeComment {
+                article: a,
+                post_index: c.post_index,
+                timestamp: c.timestamp,
+                author: super::author_name_fn(&c.author, &msg.anon),
+                date: timestamp_to_datestring(&c.timestamp, &data.timezone, &msg.date_format),
+                datetime: timestamp_to_datestring(&c.timestamp, &data.timezone, "%Y-%m-%dT%H:%M:%S%:z"),
+                text: c.text,
+            })
+        })
+        .collect();
+    if comments.is_empty() {
+        return p404(req, data).await;
+    }
+    comments.sort_by_key(|c| std::cmp::Reverse(c.timestamp));
+    let tmpl = TripcodeTemplate {
+        layout: layout_template(&req, &data),
+        tripcode: &tripcode,
+        comments,
+    };
+    actix_web::HttpResponse::Ok().body(tmpl.render().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(secret: &str) -> ring::hmac::Key {
+        ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes())
+    }
+
+    #[test]
+    fn splits_name_and_tripcode() {
+        let site = key("site");
+        let (name, tripcode) = split(" Reader #hunter2", &site);
+        assert_eq!(name, "Reader");
+        let tripcode = tripcode.unwrap();
+        // 9 bytes of the HMAC make 12 characters that can be used in a url as they are.
+        assert_eq!(tripcode.len(), 12);
+        assert!(tripcode.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+
+        assert_eq!(split("Someone else#hunter2", &site).1.as_ref(), Some(&tripcode));
+        assert_ne!(split("Reader#hunter3", &site).1.as_ref(), Some(&tripcode));
+        assert_ne!(split("Reader#hunter2", &key("other site")).1.as_ref(), Some(&tripcode));
+    }
+
+    #[test]
+    fn secret_is_everything_after_the_first_hash() {
+        let site = key("site");
+        assert_eq!(split("#a#b", &site), (String::new(), split("x#a#b", &site).1));
+        assert_ne!(split("x#a#b", &site).1, split("x#a", &site).1);
+    }
+
+    #[test]
+    fn names_without_secret_have_no_tripcode() {
+        let site = key("site");
+        assert_eq!(split("Reader", &site), ("Reader".to_string(), None));
+        assert_eq!(split("Reader#", &site), ("Reader#".to_string(), None));
+        assert_eq!(split("", &site), (String::new(), None));
+    }
+}
@@ -0,0 +1,523 @@
+use actix_web::web;
+use serde_derive::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use super::{AppState, check_public_url, mentions_dir, mentions_path, read_toml_default, unchecked_mentions_path};
+
+const MAX_REDIRECTS: usize = 5;
+const MAX_SOURCE_SIZE: usize = 1 << 20;
+const MAX_CONTENT_LENGTH: usize = 500;
+
+/// A verified webmention. Text fields are stored HTML escaped, urls are checked to be http(s).
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Mention {
+    /// Identifies the mention in the moderation queue.
+    #[serde(default)]
+    pub id: String,
+    pub source: String,
+    pub article: String,
+    /// One of reply, like, repost, bookmark or mention.
+    pub kind: String,
+    pub received: u64,
+    pub url: String,
+    pub author_name: Option<String>,
+    pub author_url: Option<String>,
+    pub author_photo: Option<String>,
+    pub title: Option<String>,
+    pub content: Option<String>,
+    pub published: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Mentions {
+    #[serde(default)]
+    pub mentions: Vec<Mention>,
+}
+
+#[derive(Deserialize)]
+pub struct WebmentionForm {
+    source: String,
+    target: String,
+}
+
+fn is_http_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+fn new_mention_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Reads the moderation queue, giving IDs to mentions queued before there were IDs.
+/// Call with the mentions lock held.
+fn read_queue() -> Mentions {
+    let mut queue: Mentions = read_toml_default(&unchecked_mentions_path());
+    if queue.mentions.iter().any(|m| m.id.is_empty()) {
+        for m in queue.mentions.iter_mut().filter(|m| m.id.is_empty()) {
+            m.id = new_mention_id();
+        }
+        std::fs::write(unchecked_mentions_path(), toml::to_string(&queue).unwrap()).unwrap();
+    }
+    queue
+}
+
+/// The mentions waiting for moderation.
+pub fn unchecked(lock: &Mutex<()>) -> Vec<Mention> {
+    let _lock_guard = lock.lock().unwrap();
+    read_queue().mentions
+}
+
+/// Resolves `url` found in the document at `base`.
+fn absolute(url: &str, base: &str) -> String {
+    let url = url.trim();
+    if is_http_url(url) {
+        return url.to_string();
+    }
+    let scheme_end = base.find("://").map_or(0, |i| i + 3);
+    let origin_end = base[scheme_end..].find('/').map_or(base.len(), |i| scheme_end + i);
+    if let Some(rest) = url.strip_prefix("//") {
+        format!("{}{}", &base[..scheme_end], rest)
+    } else if url.starts_with('/') {
+        format!("{}{}", &base[..origin_end], url)
+    } else {
+        let path = base.split(['?', '#']).next().unwrap_or(base);
+        let dir_end = path.rfind('/').filter(|&i| i >= origin_end).map_or(path.len(), |i| i + 1);
+        let dir = &path[..dir_end];
+        if dir.ends_with('/') { format!("{}{}", dir, url) } else { format!("{}/{}", dir, url) }
+    }
+}
+
+/// A start tag: offset of its `<`, lowercased name and the raw attribute text.
+struct StartTag<'a> {
+    start: usize,
+    name: String,
+    attrs: &'a str,
+}
+
+fn start_tags(html: &str) -> Vec<StartTag<'_>> {
+    let mut tags = Vec::new();
+    let mut pos = 0;
+    while let Some(i) = html[pos..].find('<') {
+        let start = pos + i;
+        let rest = &html[start + 1..];
+        if rest.starts_with("!--") {
+            pos = html[start..].find("-->").map_or(html.len(), |e| start + e + 3);
+            continue;
+        }
+        let end = rest.find('>').map_or(html.len(), |e| start + 1 + e);
+        pos = (end + 1).min(html.len());
+        if !rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            continue;
+        }
+        let tag = &html[start + 1..end];
+        let name_len = tag.find(|c: char| c.is_whitespace() || c == '/').unwrap_or(tag.len());
+        let name = tag[..name_len].to_ascii_lowercase();
+        // Markup inside scripts and styles is not part of the document.
+        if name == "script" || name == "style" {
+            pos = html[pos..].to_ascii_lowercase().find(&format!("</{}", name)).map_or(html.len(), |e| pos + e);
+        }
+        tags.push(StartTag { start, name, attrs: &tag[name_len..] });
+    }
+    tags
+}
+
+fn attr(attrs: &str, wanted: &str) -> Option<String> {
+    let mut rest = attrs;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        if rest.is_empty() {
+            return None;
+        }
+        let name_len = rest.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(rest.len());
+        let name = rest[..name_len].to_ascii_lowercase();
+        rest = rest[name_len..].trim_start();
+        let value = match rest.strip_prefix('=') {
+            None => String::new(),
+            Some(v) => {
+                let v = v.trim_start();
+                match v.chars().next() {
+                    Some(q) if q == '"' || q == '\'' => {
+                        let end = v[1..].find(q).map_or(v.len(), |e| e + 1);
+                        rest = v.get(end + 1..).unwrap_or_default();
+                        v[1..end].to_string()
+                    },
+                    _ => {
+                        let end = v.find(char::is_whitespace).unwrap_or(v.len());
+                        rest = &v[end..];
+                        v[..end].to_string()
+                    },
+                }
+            },
+        };
+        if name == wanted {
+            return Some(decode_entities(&value));
+        }
+    }
+}
+
+fn has_class(tag: &StartTag, class: &str) -> bool {
+    attr(tag.attrs, "class").is_some_and(|c| c.split_whitespace().any(|x| x == class))
+}
+
+/// Offset of the end tag closing the element that starts at `tag`.
+fn element_end(html: &str, tag: &StartTag) -> usize {
+    let void = ["area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "wbr"];
+    let body_start = html[tag.start..].find('>').map_or(html.len(), |e| tag.start + e + 1);
+    if void.contains(&tag.name.as_str()) {
+        return body_start;
+    }
+    let lower = html.to_ascii_lowercase();
+    let (open, close) = (format!("<{}", tag.name), format!("</{}", tag.name));
+    let mut depth = 1;
+    let mut pos = body_start;
+    loop {
+        let next_close = match lower[pos..].find(&close) {
+            Some(c) => pos + c,
+            None => return html.len(),
+        };
+        let next_open = lower[pos..].find(&open).map(|o| pos + o)
+            .filter(|&o| lower[o + open.len()..].starts_with(|c: char| !c.is_ascii_alphanumeric()));
+        match next_open {
+            Some(o) if o < next_close => {
+                depth += 1;
+                pos = o + open.len();
+            },
+            _ => {
+                depth -= 1;
+                if depth == 0 {
+                    return next_close;
+                }
+                pos = next_close + close.len();
+            },
+        }
+    }
+}
+
+/// Text content of an HTML fragment with whitespace collapsed.
+fn text(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(i) = rest.find('<') {
+        out.push_str(&rest[..i]);
+        out.push(' ');
+        let lower = rest[i..].to_ascii_lowercase();
+        let skip_to = ["script", "style"].iter()
+            .find(|name| lower[1..].starts_with(*name))
+            .and_then(|name| lower.find(&format!("</{}", name)))
+            .unwrap_or(0);
+        rest = rest[i + skip_to..].find('>').map_or("", |e| &rest[i + skip_to + e + 1..]);
+    }
+    out.push_str(rest);
+    decode_entities(&out).split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn truncate(text: String, max: usize) -> String {
+    match text.char_indices().nth(max) {
+        None => text,
+        Some((i, _)) => format!("{}…", &text[..i]),
+    }
+}
+
+/// The parts of the source page's h-entry shown with the mention, see https://microformats.org/wiki/h-entry
+fn parse_entry(html: &str, source: &str, target: &str) -> Mention {
+    let tags = start_tags(html);
+    let (scope_start, scope_end) = match tags.iter().find(|t| has_class(t, "h-entry")) {
+        Some(entry) => (entry.start, element_end(html, entry)),
+        None => (0, html.len()),
+    };
+    let scope: Vec<&StartTag> = tags.iter().filter(|t| t.start >= scope_start && t.start < scope_end).collect();
+    let inner = |t: &StartTag| {
+        let body_start = html[t.start..].find('>').map_or(html.len(), |e| t.start + e + 1);
+        &html[body_start.min(html.len())..element_end(html, t).max(body_start).min(html.len())]
+    };
+    let url_value = |t: &StartTag| {
+        let value = match t.name.as_str() {
+            "img" | "audio" | "video" | "source" => attr(t.attrs, "src"),
+            _ => attr(t.attrs, "href"),
+        };
+        value.map(|u| absolute(&u, source)).filter(|u| is_http_url(u))
+    };
+    let text_value = |t: &StartTag| match t.name.as_str() {
+        "img" | "area" => attr(t.attrs, "alt").unwrap_or_default(),
+        "abbr" => attr(t.attrs, "title").unwrap_or_else(|| text(inner(t))),
+        "data" | "input" => attr(t.attrs, "value").unwrap_or_default(),
+        _ => text(inner(t)),
+    };
+
+    let mut mention = Mention { source: source.to_string(), kind: "mention".to_string(), ..Default::default() };
+    let author = scope.iter().find(|t| has_class(t, "p-author"));
+    let author_range = author.map_or(0..0, |a| a.start..element_end(html, a));
+    if let Some(a) = author {
+        let within: Vec<&&StartTag> = scope.iter().filter(|t| author_range.contains(&t.start)).collect();
+        if has_class(a, "h-card") {
+            mention.author_name = within.iter().find(|t| has_class(t, "p-name")).map(|t| text_value(t))
+                .or_else(|| Some(text_value(a)));
+            mention.author_url = within.iter().find(|t| has_class(t, "u-url")).and_then(|t| url_value(t))
+                .or_else(|| url_value(a));
+            mention.author_photo = within.iter().find(|t| has_class(t, "u-photo")).and_then(|t| url_value(t));
+        } else {
+            mention.author_name = Some(text_value(a));
+            mention.author_url = url_value(a);
+        }
+    }
+    let outside_author: Vec<&&StartTag> = scope.iter().filter(|t| !author_range.contains(&t.start)).collect();
+    let find = |class: &str| outside_author.iter().find(|t| has_class(t, class));
+    mention.content = find("e-content").or_else(|| find("p-summary")).map(|t| text_value(t));
+    mention.title = find("p-name").map(|t| text_value(t))
+        // Notes have the content as their implied name.
+        .filter(|name| mention.content.as_ref().is_none_or(|c| !c.starts_with(name.as_str())));
+    mention.published = find("dt-published").map(|t| attr(t.attrs, "datetime").unwrap_or_else(|| text_value(t)));
+    mention.url = find("u-url").and_then(|t| url_value(t)).unwrap_or_else(|| source.to_string());
+    for (class, kind) in &[("u-in-reply-to", "reply"), ("u-like-of", "like"), ("u-repost-of", "repost"), ("u-bookmark-of", "bookmark")] {
+        if outside_author.iter().any(|t| has_class(t, class) && url_value(t).as_deref() == Some(target)) {
+            mention.kind = kind.to_string();
+            break;
+        }
+    }
+
+    let clean = |value: Option<String>| value.map(|v| escape(v.trim())).filter(|v| !v.is_empty());
+    Mention {
+        author_name: clean(mention.author_name.map(|n| truncate(n, 100))),
+        author_url: clean(mention.author_url),
+        author_photo: clean(mention.author_photo),
+        title: clean(mention.title.map(|t| truncate(t, 200))),
+        content: clean(mention.content.map(|c| truncate(c, MAX_CONTENT_LENGTH))),
+        published: clean(mention.published.map(|p| truncate(p, 40))),
+        url: escape(&mention.url),
+        source: escape(source),
+        ..mention
+    }
+}
+
+/// The spec requires an exact match of the target url in the source.
+fn links_to(html: &str, source: &str, target: &str) -> bool {
+    start_tags(html).iter().any(|t| attr(t.attrs, "href").is_some_and(|h| absolute(&h, source) == target))
+}
+
+/// Fetches `url` following redirects, returning the final status and body. Unless `allow_private_hosts`
+/// is set, every url on the way has to resolve to public addresses.
+async fn fetch(url: &str, allow_private_hosts: bool) -> Result<(u16, String), String> {
+    let client = actix_web::client::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .finish();
+    let mut current = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        if !allow_private_hosts {
+            check_public_url(&current).await?;
+        }
+        let mut response = client.get(&current)
+            .header(actix_web::http::header::USER_AGENT, "Lesser Scholar webmention verifier")
+            .header(actix_web::http::header::ACCEPT, "text/html, text/plain;q=0.5")
+            .send().await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        if status.is_redirection() {
+            let location = response.headers().get(actix_web::http::header::LOCATION)
+                .and_then(|l| l.to_str().ok())
+                .ok_or("redirect without location")?;
+            current = absolute(location, &current);
+            if !is_http_url(&current) {
+                return Err("redirect to a non http url".to_string());
+            }
+            continue;
+        }
+        let body = response.body().limit(MAX_SOURCE_SIZE).await.map_err(|e| e.to_string())?;
+        return Ok((status.as_u16(), String::from_utf8_lossy(&body).to_string()));
+    }
+    Err("too many redirects".to_string())
+}
+
+/// Checks that `source` links to `target` and queues the mention for moderation. A source that is gone
+/// or no longer links to the article removes the earlier mention, a changed one goes back to moderation.
+async fn verify(source: String, target: String, article: String, lock: Arc<Mutex<()>>) {
+    let mention = match fetch(&source, false).await {
+        Ok((status, body)) if (200..300).contains(&status) => match links_to(&body, &source, &target) {
+            true => Some(parse_entry(&body, &source, &target)),
+            false => None,
+        },
+        Ok((410, _)) => None,
+        _ => return,
+    };
+    let escaped_source = escape(&source);
+    let _lock_guard = lock.lock().unwrap();
+    let mut queue = read_queue();
+    queue.mentions.retain(|m| !(m.source == escaped_source && m.article == article));
+    let mut approved: Mentions = read_toml_default(&mentions_path(&article));
+    let approved_count = approved.mentions.len();
+    approved.mentions.retain(|m| m.source != escaped_source);
+    if approved.mentions.len() != approved_count {
+        std::fs::write(mentions_path(&article), toml::to_string(&approved).unwrap()).unwrap();
+    }
+    if let Some(mention) = mention {
+        queue.mentions.push(Mention {
+            id: new_mention_id(),
+            article,
+            received: std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+            ..mention
+        });
+    }
+    std::fs::write(unchecked_mentions_path(), toml::to_string(&queue).unwrap()).unwrap();
+}
+
+/// Name of the public article `target` points at, if any.
+fn target_article(data: &AppState, target: &str) -> Option<String> {
+    let path = target.strip_prefix(&data.config.base_url)?.strip_prefix("/a/")?;
+    let path = path.split(['#', '?']).next()?.trim_end_matches('/');
+    let name = percent_encoding::percent_decode_str(path).decode_utf8().ok()?;
+    let meta = data.meta();
+    match meta.articles_map.get(name.as_ref()) {
+        Some((a, _)) if a.is_public() => Some(a.name.clone()),
+        _ => None,
+    }
+}
+
+/// Receiver for https://www.w3.org/TR/webmention/, verification happens after responding.
+pub async fn receive(web::Form(form): web::Form<WebmentionForm>, data: web::Data<AppState>) -> actix_web::HttpResponse {
+    let bad_request = |reason: &str| actix_web::HttpResponse::BadRequest()
+        .content_type("text/plain; charset=utf-8")
+        .body(reason.to_string());
+    if !is_http_url(&form.source) || !is_http_url(&form.target) {
+        return bad_request("Source and target must be http or https urls.");
+    }
+    if form.source == form.target {
+        return bad_request("Source and target must be different.");
+    }
+    let article = match target_article(&data, &form.target) {
+        Some(article) => article,
+        None => return bad_request("Target is not an article on this site."),
+    };
+    actix_web::rt::spawn(verify(form.source, form.target, article, data.mentions_file_lock.clone()));
+    actix_web::HttpResponse::Accepted()
+        .content_type("text/plain; charset=utf-8")
+        .body("Accepted, the source will be verified and the mention moderated.")
+}
+
+/// Applies the moderation decisions for the queued mentions, by mention ID. Returns the IDs of
+/// decisions for mentions that are not in the queue.
+pub fn moderate(decisions: &[(String, &str)], lock: &Mutex<()>) -> Vec<String> {
+    let _lock_guard = lock.lock().unwrap();
+    let queue = read_queue();
+    let missing = decisions.iter()
+        .filter(|(id, _)| !queue.mentions.iter().any(|m| m.id == *id))
+        .map(|(id, _)| id.clone())
+        .collect();
+    let mut remaining = Mentions::default();
+    let mut approved: Vec<Mention> = Vec::new();
+    for m in queue.mentions {
+        match decisions.iter().find(|(id, _)| *id == m.id).map(|d| d.1) {
+            Some("approve") => approved.push(m),
+            Some("delete") => (),
+            _ => remaining.mentions.push(m),
+        }
+    }
+    std::fs::create_dir_all(mentions_dir()).unwrap();
+    for m in approved {
+        let mut mentions: Mentions = read_toml_default(&mentions_path(&m.article));
+        let path = mentions_path(&m.article);
+        mentions.mentions.push(m);
+        std::fs::write(path, toml::to_string(&mentions).unwrap()).unwrap();
+    }
+    std::fs::write(unchecked_mentions_path(), toml::to_string(&remaining).unwrap()).unwrap();
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    const TARGET: &str = "http://blog.example/a/mindstorms";
+
+    /// Serves a few fixed pages on a local port, returning its base url.
+    fn stand_in_site() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request.split_whitespace().nth(1).unwrap_or("/");
+                let (status, headers, body) = match path {
+                    "/reply" => ("200 OK", "", format!(r#"<html><body><article class="h-entry">
+<a class="u-url" href="/reply">Permalink</a>
+<span class="p-author h-card"><a class="p-name u-url" href="https://alice.example/">Alice &amp; co</a> <img class="u-photo" src="/alice.png" alt=""></span>
+<div class="e-content">Great <b>article</b>, <a class="u-in-reply-to" href="{}">replying</a>.</div>
+<time class="dt-published" datetime="2020-10-01T12:00:00Z">1 October</time>
+<script>document.write('<a class="p-name">Not a name</a>');</script>
+</article></body></html>"#, TARGET)),
+                    "/moved" => ("301 Moved Permanently", "Location: /reply\r\n", String::new()),
+                    "/unrelated" => ("200 OK", "", r#"<p>Links to <a href="http://blog.example/a/other">another article</a>.</p>"#.to_string()),
+                    _ => ("404 Not Found", "", String::new()),
+                };
+                let _ = write!(stream, "HTTP/1.1 {}\r\n{}Content-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status, headers, body.len(), body);
+            }
+        });
+        base
+    }
+
+    fn blocking_fetch(url: &str, allow_private_hosts: bool) -> Result<(u16, String), String> {
+        let url = url.to_string();
+        actix_web::rt::System::new("webmention-test").block_on(async move { fetch(&url, allow_private_hosts).await })
+    }
+
+    #[test]
+    fn verifies_and_parses_reply() {
+        let base = stand_in_site();
+        let source = format!("{}/moved", base);
+        let (status, body) = blocking_fetch(&source, true).unwrap();
+        assert_eq!(status, 200);
+        assert!(links_to(&body, &source, TARGET));
+
+        let mention = parse_entry(&body, &source, TARGET);
+        assert_eq!(mention.kind, "reply");
+        assert_eq!(mention.url, format!("{}/reply", base));
+        assert_eq!(mention.author_name.as_deref(), Some("Alice &amp; co"));
+        assert_eq!(mention.author_url.as_deref(), Some("https://alice.example/"));
+        assert_eq!(mention.author_photo, Some(format!("{}/alice.png", base)));
+        assert_eq!(mention.content.as_deref(), Some("Great article , replying ."));
+        assert_eq!(mention.published.as_deref(), Some("2020-10-01T12:00:00Z"));
+        assert_eq!(mention.title, None);
+    }
+
+    #[test]
+    fn source_without_link_is_not_verified() {
+        let base = stand_in_site();
+        let source = format!("{}/unrelated", base);
+        let (status, body) = blocking_fetch(&source, true).unwrap();
+        assert_eq!(status, 200);
+        assert!(!links_to(&body, &source, TARGET));
+        assert_eq!(blocking_fetch(&format!("{}/missing", base), true).unwrap().0, 404);
+    }
+
+    #[test]
+    fn private_sources_are_not_fetched() {
+        let base = stand_in_site();
+        assert!(blocking_fetch(&format!("{}/reply", base), false).is_err());
+        assert!(blocking_fetch("http://[::1]/", false).is_err());
+        assert!(blocking_fetch("http://10.0.0.1/", false).is_err());
+        assert!(blocking_fetch("http://169.254.169.254/latest/meta-data/", false).is_err());
+    }
+}